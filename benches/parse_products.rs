@@ -0,0 +1,95 @@
+//! Parses a products response of 100 listings, as the client does for every page it receives.
+//!
+//! Besides the timings of criterion, prints the number of allocations and bytes allocated by a
+//! single parse.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use holland2stay_rs::api::{City, parse_products_response};
+use reqwest::Url;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ITEMS: usize = 100;
+
+/// The fixture response with its listings repeated under distinct skus up to `ITEMS` listings.
+fn response() -> Vec<u8> {
+    let mut response: serde_json::Value =
+        serde_json::from_str(include_str!("../fixtures/products_rotterdam.json"))
+            .expect("could not parse fixture");
+    let products = &mut response["data"]["products"];
+    let fixture_items = products["items"]
+        .as_array()
+        .expect("fixture has no items")
+        .clone();
+    let items: Vec<serde_json::Value> = fixture_items
+        .iter()
+        .cycle()
+        .take(ITEMS)
+        .enumerate()
+        .map(|(i, item)| {
+            let mut item = item.clone();
+            item["sku"] = format!("{}-{}", item["sku"].as_str().unwrap(), i).into();
+            item
+        })
+        .collect();
+    products["items"] = items.into();
+    products["total_count"] = ITEMS.into();
+    serde_json::to_vec(&response).unwrap()
+}
+
+fn parse_products(c: &mut Criterion) {
+    let body = response();
+    let cities = [City {
+        id: "25".parse().unwrap(),
+        name: "Rotterdam".to_string(),
+    }];
+    let residences_url = Url::parse("https://holland2stay.com/residences/").unwrap();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let listings = parse_products_response(&body, &cities, &residences_url).unwrap();
+    println!(
+        "parsing {} listings ({} bytes) takes {} allocations of {} bytes in total",
+        listings.houses.len(),
+        body.len(),
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes,
+    );
+    drop(listings);
+
+    c.bench_function("parse 100 listings", |b| {
+        b.iter(|| parse_products_response(black_box(&body), &cities, &residences_url).unwrap())
+    });
+}
+
+criterion_group!(benches, parse_products);
+criterion_main!(benches);
@@ -0,0 +1,528 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use reqwest::Url;
+
+use crate::api::{AuthenticatedClient, Holland2StayClient};
+use crate::auth::{
+    Auth, AuthError, Login, SessionStore, SessionStoreError, holland2stay_base_url,
+    login_holland2stay_at, restore_or_login_holland2stay_at, write_private_file,
+};
+
+/// The environment variable holding the base64 encoded 32 byte key credentials are encrypted with.
+pub const CREDENTIALS_KEY_VAR: &str = "HOLLAND2STAY_CREDENTIALS_KEY";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccountError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Session(#[from] SessionStoreError),
+
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("The credentials key must be 32 bytes, not {0}")]
+    InvalidKeyLength(usize),
+
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
+
+    /// Logging in with the credentials to link failed.
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CipherError {
+    #[error("Could not encrypt")]
+    Encryption,
+
+    /// The data was encrypted with another key, or the file was tampered with.
+    #[error("Could not decrypt")]
+    Decryption,
+}
+
+/// The key credentials and sessions are encrypted with at rest.
+#[derive(Clone)]
+pub struct CredentialsKey(Key<Aes256Gcm>);
+
+impl std::fmt::Debug for CredentialsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CredentialsKey(..)")
+    }
+}
+
+impl CredentialsKey {
+    pub fn from_base64(key: &str) -> Result<Self, AccountError> {
+        let key = BASE64_STANDARD.decode(key.trim())?;
+        if key.len() != 32 {
+            return Err(AccountError::InvalidKeyLength(key.len()));
+        }
+        Ok(CredentialsKey(*Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    /// The key in [`CREDENTIALS_KEY_VAR`], or `None` if it is not set.
+    pub fn from_env() -> Option<Result<Self, AccountError>> {
+        let key = std::env::var(CREDENTIALS_KEY_VAR).ok()?;
+        Some(Self::from_base64(&key))
+    }
+}
+
+/// Encrypted data as it is saved on disk.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Encrypted {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Encrypts `plaintext` with a new nonce, into the json the nonce and ciphertext are saved as.
+pub(crate) fn encrypt(key: &CredentialsKey, plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(&key.0)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| CipherError::Encryption)?;
+    let encrypted = Encrypted {
+        nonce: BASE64_STANDARD.encode(nonce),
+        ciphertext: BASE64_STANDARD.encode(ciphertext),
+    };
+    Ok(serde_json::to_vec(&encrypted).expect("Could not serialize encrypted data"))
+}
+
+/// Decrypts what [`encrypt`] returned.
+pub(crate) fn decrypt(key: &CredentialsKey, contents: &[u8]) -> Result<Vec<u8>, CipherError> {
+    let encrypted: Encrypted =
+        serde_json::from_slice(contents).map_err(|_| CipherError::Decryption)?;
+    let nonce = BASE64_STANDARD
+        .decode(encrypted.nonce)
+        .ok()
+        .filter(|nonce| nonce.len() == 12)
+        .ok_or(CipherError::Decryption)?;
+    let ciphertext = BASE64_STANDARD
+        .decode(encrypted.ciphertext)
+        .map_err(|_| CipherError::Decryption)?;
+    Aes256Gcm::new(&key.0)
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| CipherError::Decryption)
+}
+
+/// The holland2stay accounts users linked, by user id. Every account has a file with its
+/// credentials and one with its session, both encrypted, in `dir` and only readable by their
+/// owner.
+pub struct LinkedAccounts {
+    dir: PathBuf,
+    key: CredentialsKey,
+    /// The clients of the accounts logged in to since the start, which keep their sessions alive.
+    clients: Mutex<HashMap<i64, Arc<AuthenticatedClient>>>,
+    /// Held while the account of a user is linked, logged in to or unlinked, so that an unlink
+    /// cannot be undone by a login that was still running.
+    user_locks: Mutex<HashMap<i64, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl LinkedAccounts {
+    pub fn new(dir: impl Into<PathBuf>, key: &CredentialsKey) -> Self {
+        LinkedAccounts {
+            dir: dir.into(),
+            key: key.clone(),
+            clients: Mutex::default(),
+            user_locks: Mutex::default(),
+        }
+    }
+
+    fn credentials_path(&self, user_id: i64) -> PathBuf {
+        self.dir.join(format!("{}.credentials.json", user_id))
+    }
+
+    /// Where the session of the account of `user_id` is kept between runs. The session is only
+    /// saved while the account is linked, so a client still in use after an unlink does not
+    /// save it again.
+    pub fn session_store(&self, user_id: i64) -> SessionStore {
+        SessionStore::encrypted(
+            self.dir.join(format!("{}.session.json", user_id)),
+            &self.key,
+        )
+        .while_exists(self.credentials_path(user_id))
+    }
+
+    async fn lock_user(&self, user_id: i64) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self
+            .user_locks
+            .lock()
+            .expect("user locks lock poisoned")
+            .entry(user_id)
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// The ids of the users that linked an account, in no particular order.
+    pub fn user_ids(&self) -> Result<Vec<i64>, AccountError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut user_ids = vec![];
+        for entry in entries {
+            let file_name = entry?.file_name();
+            if let Some(user_id) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".credentials.json"))
+                .and_then(|user_id| user_id.parse().ok())
+            {
+                user_ids.push(user_id);
+            }
+        }
+        Ok(user_ids)
+    }
+
+    pub async fn link(&self, user_id: i64, auth: &Auth) -> Result<Login, AccountError> {
+        self.link_at(&holland2stay_base_url(), user_id, auth).await
+    }
+
+    /// Logs in to the holland2stay website hosted at `base_url` and, if that succeeds, saves the
+    /// session and credentials of `user_id`, replacing those of the account linked before.
+    pub async fn link_at(
+        &self,
+        base_url: &Url,
+        user_id: i64,
+        auth: &Auth,
+    ) -> Result<Login, AccountError> {
+        let _user_lock = self.lock_user(user_id).await;
+        // Always log in, a stored session does not tell whether the password is right.
+        let mut login = login_holland2stay_at(base_url, auth).await?;
+        self.forget_client(user_id);
+        // The credentials go first, the session store only saves while they exist.
+        self.save_credentials(user_id, auth)?;
+        if let Err(err) = login.persist_to(self.session_store(user_id)) {
+            self.remove_files(user_id)?;
+            return Err(err.into());
+        }
+        Ok(login)
+    }
+
+    fn save_credentials(&self, user_id: i64, auth: &Auth) -> Result<(), AccountError> {
+        let contents = encrypt(&self.key, &serde_json::to_vec(auth)?)?;
+        write_private_file(&self.credentials_path(user_id), &contents)?;
+        Ok(())
+    }
+
+    /// The credentials of `user_id`, or `None` if they did not link an account.
+    pub fn credentials(&self, user_id: i64) -> Result<Option<Auth>, AccountError> {
+        let contents = match fs::read(self.credentials_path(user_id)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let plaintext = decrypt(&self.key, &contents)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+
+    pub async fn login(&self, user_id: i64) -> Result<Option<Login>, AccountError> {
+        self.login_at(&holland2stay_base_url(), user_id).await
+    }
+
+    /// A login to the linked account of `user_id` on the holland2stay website hosted at
+    /// `base_url`, or `None` if they did not link one. The session saved by the last run is
+    /// continued while holland2stay still accepts it, so a restart does not log in again.
+    pub async fn login_at(
+        &self,
+        base_url: &Url,
+        user_id: i64,
+    ) -> Result<Option<Login>, AccountError> {
+        let _user_lock = self.lock_user(user_id).await;
+        self.restore_login(base_url, user_id).await
+    }
+
+    /// [`Self::login_at`], for callers that hold the lock of `user_id`.
+    async fn restore_login(
+        &self,
+        base_url: &Url,
+        user_id: i64,
+    ) -> Result<Option<Login>, AccountError> {
+        let Some(auth) = self.credentials(user_id)? else {
+            return Ok(None);
+        };
+        let login =
+            restore_or_login_holland2stay_at(base_url, &auth, self.session_store(user_id)).await?;
+        Ok(Some(login))
+    }
+
+    pub async fn client(
+        &self,
+        public: &Holland2StayClient,
+        user_id: i64,
+    ) -> Result<Option<Arc<AuthenticatedClient>>, AccountError> {
+        self.client_at(&holland2stay_base_url(), public, user_id)
+            .await
+    }
+
+    /// A client making requests on behalf of the linked account of `user_id`, or `None` if they
+    /// did not link one. The client is kept, so that later requests continue its session instead
+    /// of restoring it from disk again.
+    pub async fn client_at(
+        &self,
+        base_url: &Url,
+        public: &Holland2StayClient,
+        user_id: i64,
+    ) -> Result<Option<Arc<AuthenticatedClient>>, AccountError> {
+        let _user_lock = self.lock_user(user_id).await;
+        if let Some(client) = self.lock_clients().get(&user_id) {
+            return Ok(Some(client.clone()));
+        }
+        let Some(login) = self.restore_login(base_url, user_id).await? else {
+            return Ok(None);
+        };
+        let client = Arc::new(public.authenticated(login));
+        self.lock_clients().insert(user_id, client.clone());
+        Ok(Some(client))
+    }
+
+    fn lock_clients(&self) -> std::sync::MutexGuard<'_, HashMap<i64, Arc<AuthenticatedClient>>> {
+        self.clients.lock().expect("clients lock poisoned")
+    }
+
+    /// Drops the client of `user_id`, whose account was linked again or unlinked.
+    fn forget_client(&self, user_id: i64) {
+        self.lock_clients().remove(&user_id);
+    }
+
+    /// Deletes the credentials and session of `user_id`. Returns whether they had linked an account.
+    pub async fn unlink(&self, user_id: i64) -> Result<bool, AccountError> {
+        let _user_lock = self.lock_user(user_id).await;
+        self.forget_client(user_id);
+        self.remove_files(user_id)
+    }
+
+    /// Deletes the credentials of `user_id` before their session, see [`SessionStore::while_exists`].
+    fn remove_files(&self, user_id: i64) -> Result<bool, AccountError> {
+        let linked = match fs::remove_file(self.credentials_path(user_id)) {
+            Ok(()) => true,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err.into()),
+        };
+        self.session_store(user_id).remove()?;
+        Ok(linked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, FakeHolland2Stay, auth};
+
+    fn key() -> CredentialsKey {
+        CredentialsKey::from_base64(&BASE64_STANDARD.encode([7; 32])).unwrap()
+    }
+
+    #[test]
+    fn test_credentials_key() {
+        assert!(matches!(
+            CredentialsKey::from_base64(&BASE64_STANDARD.encode([7; 16])),
+            Err(AccountError::InvalidKeyLength(16))
+        ));
+        assert!(matches!(
+            CredentialsKey::from_base64("not base64!"),
+            Err(AccountError::Base64(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_link_and_unlink() {
+        let server = FakeHolland2Stay::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = LinkedAccounts::new(dir.path().join("accounts"), &key());
+        assert!(accounts.credentials(42).unwrap().is_none());
+        assert!(accounts.user_ids().unwrap().is_empty());
+
+        accounts
+            .link_at(&server.base_url(), 42, &auth())
+            .await
+            .unwrap();
+        let contents = fs::read_to_string(accounts.credentials_path(42)).unwrap();
+        assert!(!contents.contains("hunter2"));
+        assert!(!contents.contains("jane@example.com"));
+        let session = fs::read_to_string(accounts.session_store(42).path()).unwrap();
+        let bearer_token = test_server::fixture("session.json")["accessToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(!session.contains(&bearer_token));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(accounts.credentials_path(42))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let credentials = accounts.credentials(42).unwrap().unwrap();
+        assert_eq!(credentials.username(), "jane@example.com");
+        assert!(accounts.credentials(43).unwrap().is_none());
+        assert_eq!(accounts.user_ids().unwrap(), vec![42]);
+
+        // The encrypted session is continued instead of logging in again, like after a restart.
+        let login = accounts
+            .login_at(&server.base_url(), 42)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(login.bearer_token(), bearer_token);
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let logins = requests
+            .iter()
+            .filter(|request| request.url.path() == "/api/auth/callback/credentials")
+            .count();
+        assert_eq!(logins, 1);
+        assert!(
+            accounts
+                .login_at(&server.base_url(), 43)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        assert!(accounts.unlink(42).await.unwrap());
+        assert!(accounts.credentials(42).unwrap().is_none());
+        assert!(!accounts.session_store(42).path().exists());
+        assert!(!accounts.unlink(42).await.unwrap());
+        assert!(accounts.user_ids().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_link_with_wrong_password_and_stored_session() {
+        let server = FakeHolland2Stay::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = LinkedAccounts::new(dir.path(), &key());
+        restore_or_login_holland2stay_at(&server.base_url(), &auth(), accounts.session_store(42))
+            .await
+            .unwrap();
+
+        let wrong_auth = Auth::new("jane@example.com".to_string(), "wrong".to_string());
+        let error = accounts
+            .link_at(&server.base_url(), 42, &wrong_auth)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            AccountError::Auth(AuthError::InvalidCredentials)
+        ));
+        assert!(!accounts.credentials_path(42).exists());
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let server = FakeHolland2Stay::start().await;
+        let public = server.client_builder().build().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = LinkedAccounts::new(dir.path(), &key());
+        assert!(
+            accounts
+                .client_at(&server.base_url(), &public, 42)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        accounts
+            .link_at(&server.base_url(), 42, &auth())
+            .await
+            .unwrap();
+
+        let client = accounts
+            .client_at(&server.base_url(), &public, 42)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.query_reservations().await.unwrap().len(), 1);
+        let same_client = accounts
+            .client_at(&server.base_url(), &public, 42)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(Arc::ptr_eq(&client, &same_client));
+
+        accounts.unlink(42).await.unwrap();
+        assert!(
+            accounts
+                .client_at(&server.base_url(), &public, 42)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unlink_during_client() {
+        use wiremock::{
+            Mock, ResponseTemplate,
+            matchers::{method, path},
+        };
+
+        let server = FakeHolland2Stay::start().await;
+        let public = server.client_builder().build().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Arc::new(LinkedAccounts::new(dir.path(), &key()));
+        accounts
+            .link_at(&server.base_url(), 42, &auth())
+            .await
+            .unwrap();
+        // Checking the stored session takes long enough for the unlink to start meanwhile.
+        let mut session = test_server::fixture("session.json");
+        session["expires"] = (chrono::Utc::now() + chrono::TimeDelta::days(30))
+            .to_rfc3339()
+            .into();
+        Mock::given(method("GET"))
+            .and(path("/api/auth/session"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(session)
+                    .set_delay(std::time::Duration::from_millis(200)),
+            )
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+
+        let client = tokio::spawn({
+            let accounts = accounts.clone();
+            let base_url = server.base_url();
+            async move { accounts.client_at(&base_url, &public, 42).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(accounts.unlink(42).await.unwrap());
+        client.await.unwrap().unwrap();
+
+        assert!(!accounts.session_store(42).path().exists());
+        assert!(!accounts.credentials_path(42).exists());
+        assert!(accounts.lock_clients().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_credentials_with_another_key() {
+        let server = FakeHolland2Stay::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        LinkedAccounts::new(dir.path(), &key())
+            .link_at(&server.base_url(), 42, &auth())
+            .await
+            .unwrap();
+        let other_key = CredentialsKey::from_base64(&BASE64_STANDARD.encode([8; 32])).unwrap();
+        assert!(matches!(
+            LinkedAccounts::new(dir.path(), &other_key).credentials(42),
+            Err(AccountError::Cipher(CipherError::Decryption))
+        ));
+    }
+}
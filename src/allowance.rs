@@ -0,0 +1,347 @@
+//! A rough estimate of rent allowance (huurtoeslag). The rules are a simplification of those of the
+//! Belastingdienst, which only decides on the actual allowance once a request is made.
+
+use std::{io, path::Path, str::FromStr};
+
+use rust_decimal::Decimal;
+
+use crate::api::House;
+
+/// The environment variable holding the path of a json file with the [`AllowanceRules`] to use
+/// instead of the defaults, for when the limits change. Limits left out of the file keep their
+/// default.
+pub const ALLOWANCE_RULES_VAR: &str = "HOLLAND2STAY_ALLOWANCE_RULES";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AllowanceRulesError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+}
+
+/// The age and income the allowance of a user depends on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllowanceProfile {
+    pub age: u32,
+    /// Yearly taxable income in euros.
+    pub yearly_income: Decimal,
+}
+
+impl AllowanceProfile {
+    /// The highest yearly income accepted, far above any allowance limit.
+    pub const MAX_YEARLY_INCOME: Decimal = Decimal::from_parts(10_000_000, 0, 0, false, 0);
+
+    /// Parses "<age> <yearly income>", see [`parse_income`] for the income. Rejects an age of 0
+    /// and incomes above [`Self::MAX_YEARLY_INCOME`].
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut words = text.split_whitespace();
+        let age = words.next()?.parse().ok().filter(|&age| age > 0)?;
+        let yearly_income =
+            parse_income(words.next()?).filter(|&income| income <= Self::MAX_YEARLY_INCOME)?;
+        words
+            .next()
+            .is_none()
+            .then_some(AllowanceProfile { age, yearly_income })
+    }
+}
+
+/// Parses an amount in euros such as "28000", "28.000", "28,000.50" or "28000,5". A separator
+/// followed by one or two digits is the decimal separator, the others must separate thousands.
+/// Anything else is rejected rather than guessed at.
+fn parse_income(text: &str) -> Option<Decimal> {
+    let (whole, cents) = match text.rfind(['.', ',']) {
+        Some(separator) if text.len() - separator - 1 <= 2 => {
+            (&text[..separator], Some(&text[separator + 1..]))
+        }
+        _ => (text, None),
+    };
+    let is_number = |digits: &str| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit());
+    let mut groups = whole.split(['.', ',']);
+    let first_group = groups.next()?;
+    let mut amount = first_group.to_string();
+    for group in groups {
+        if first_group.len() > 3 || group.len() != 3 {
+            return None;
+        }
+        amount.push_str(group);
+    }
+    if !is_number(&amount) || cents.is_some_and(|cents| !is_number(cents)) {
+        return None;
+    }
+    if let Some(cents) = cents {
+        amount.push('.');
+        amount.push_str(cents);
+    }
+    Decimal::from_str(&amount).ok()
+}
+
+/// The limits of the allowance calculation, per month unless stated otherwise. The defaults are
+/// approximately the limits of [`AllowanceRules::DEFAULT_YEAR`] for a single person household,
+/// other limits can be loaded with [`AllowanceRules::from_env`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct AllowanceRules {
+    /// Rent up to this limit is fully compensated above the base rent (kwaliteitskortingsgrens).
+    pub quality_discount_limit: Decimal,
+    /// Rent between the quality discount limit and this limit is partly compensated
+    /// (aftoppingsgrens).
+    pub capping_limit: Decimal,
+    /// The share of the rent between the two limits above that is compensated.
+    pub capping_rate: Decimal,
+    /// No allowance is given if the rent is higher than this (maximale huurgrens).
+    pub maximum_rent: Decimal,
+    /// The age below which the rent may not exceed the quality discount limit instead.
+    pub young_age_limit: u32,
+    /// The part of the rent everyone pays themselves (minimumbasishuur).
+    pub minimum_base_rent: Decimal,
+    /// The yearly income above which the base rent goes up.
+    pub base_rent_income_threshold: Decimal,
+    /// How much the monthly base rent goes up per euro of yearly income above the threshold.
+    pub base_rent_income_rate: Decimal,
+    /// No allowance is given to people with a higher yearly income, if set.
+    pub maximum_yearly_income: Option<Decimal>,
+}
+
+impl Default for AllowanceRules {
+    fn default() -> Self {
+        AllowanceRules {
+            quality_discount_limit: Decimal::new(47720, 2),
+            capping_limit: Decimal::new(68296, 2),
+            capping_rate: Decimal::new(65, 2),
+            maximum_rent: Decimal::new(90007, 2),
+            young_age_limit: 23,
+            minimum_base_rent: Decimal::new(23821, 2),
+            base_rent_income_threshold: Decimal::new(20_000, 0),
+            base_rent_income_rate: Decimal::new(15, 3),
+            maximum_yearly_income: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, derive_more::Display)]
+pub enum Ineligibility {
+    #[display("the rent of {rent:.2} is above the limit of {limit:.2}")]
+    RentTooHigh { rent: Decimal, limit: Decimal },
+    #[display("the income of {income:.2} is above the limit of {limit:.2}")]
+    IncomeTooHigh { income: Decimal, limit: Decimal },
+    #[display("the rent of {rent:.2} is below the base rent of {base_rent:.2}")]
+    RentBelowBaseRent { rent: Decimal, base_rent: Decimal },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllowanceEstimate {
+    /// The estimated monthly allowance, or why there is none.
+    pub allowance: Result<Decimal, Ineligibility>,
+    /// The total monthly costs of the house minus the allowance.
+    pub net_monthly_cost: Option<Decimal>,
+}
+
+impl AllowanceEstimate {
+    pub fn is_eligible(&self) -> bool {
+        self.allowance.is_ok()
+    }
+}
+
+impl std::fmt::Display for AllowanceEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.allowance {
+            Ok(allowance) => write!(f, "about {allowance:.2} allowance")?,
+            Err(reason) => write!(f, "no allowance, {reason}")?,
+        }
+        if let Some(net_monthly_cost) = self.net_monthly_cost {
+            write!(f, ", {net_monthly_cost:.2} net per month")?;
+        }
+        Ok(())
+    }
+}
+
+impl AllowanceRules {
+    /// The year the default limits are those of.
+    pub const DEFAULT_YEAR: i32 = 2025;
+
+    /// Reads the rules from a json file with the fields of [`AllowanceRules`].
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AllowanceRulesError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// The rules in the file [`ALLOWANCE_RULES_VAR`] points to, or `None` if it is not set.
+    pub fn from_env() -> Option<Result<Self, AllowanceRulesError>> {
+        let path = std::env::var(ALLOWANCE_RULES_VAR).ok()?;
+        Some(Self::from_file(path))
+    }
+
+    /// The part of the rent someone with `yearly_income` pays themselves.
+    pub fn base_rent(&self, yearly_income: Decimal) -> Decimal {
+        let taxed_income = (yearly_income - self.base_rent_income_threshold).max(Decimal::ZERO);
+        (self.minimum_base_rent + taxed_income * self.base_rent_income_rate).round_dp(2)
+    }
+
+    /// The monthly allowance when `rent` is the part of the rent that counts towards the allowance.
+    pub fn allowance(
+        &self,
+        profile: &AllowanceProfile,
+        rent: Decimal,
+    ) -> Result<Decimal, Ineligibility> {
+        let rent_limit = if profile.age < self.young_age_limit {
+            self.quality_discount_limit
+        } else {
+            self.maximum_rent
+        };
+        if rent > rent_limit {
+            return Err(Ineligibility::RentTooHigh {
+                rent,
+                limit: rent_limit,
+            });
+        }
+        if let Some(limit) = self
+            .maximum_yearly_income
+            .filter(|&limit| profile.yearly_income > limit)
+        {
+            return Err(Ineligibility::IncomeTooHigh {
+                income: profile.yearly_income,
+                limit,
+            });
+        }
+        let base_rent = self.base_rent(profile.yearly_income);
+        let fully_compensated =
+            (rent.min(self.quality_discount_limit) - base_rent).max(Decimal::ZERO);
+        let partly_compensated = (rent.min(self.capping_limit)
+            - self.quality_discount_limit.max(base_rent))
+        .max(Decimal::ZERO);
+        let allowance = (fully_compensated + partly_compensated * self.capping_rate).round_dp(2);
+        if allowance.is_zero() {
+            return Err(Ineligibility::RentBelowBaseRent { rent, base_rent });
+        }
+        Ok(allowance)
+    }
+
+    /// Estimates the allowance for `house`, or `None` if holland2stay does not list the part of its
+    /// rent that counts towards the allowance.
+    pub fn estimate(&self, profile: &AllowanceProfile, house: &House) -> Option<AllowanceEstimate> {
+        let allowance = self.allowance(profile, house.allowance_price?);
+        let total = house
+            .costs
+            .total()
+            .or_else(|| house.price.as_ref().map(|price| price.amount));
+        Some(AllowanceEstimate {
+            allowance,
+            net_monthly_cost: total.map(|total| total - allowance.unwrap_or_default()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(age: u32, yearly_income: i64) -> AllowanceProfile {
+        AllowanceProfile {
+            age,
+            yearly_income: Decimal::new(yearly_income, 0),
+        }
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        assert_eq!(
+            AllowanceProfile::parse("24 28.000"),
+            Some(profile(24, 28_000))
+        );
+        assert_eq!(
+            AllowanceProfile::parse("24 1,234,567"),
+            Some(profile(24, 1_234_567))
+        );
+        assert_eq!(
+            AllowanceProfile::parse("24 28000.50"),
+            Some(AllowanceProfile {
+                age: 24,
+                yearly_income: Decimal::new(2800050, 2)
+            })
+        );
+        assert_eq!(
+            AllowanceProfile::parse("24 28.000,5"),
+            Some(AllowanceProfile {
+                age: 24,
+                yearly_income: Decimal::new(280005, 1)
+            })
+        );
+        assert_eq!(AllowanceProfile::parse("24 0"), Some(profile(24, 0)));
+        assert_eq!(AllowanceProfile::parse("24 28000.505"), None);
+        assert_eq!(AllowanceProfile::parse("24 2.80.00"), None);
+        assert_eq!(AllowanceProfile::parse("24 28000."), None);
+        assert_eq!(AllowanceProfile::parse("24 .5"), None);
+        assert_eq!(AllowanceProfile::parse("24"), None);
+        assert_eq!(AllowanceProfile::parse("24 28000 euro"), None);
+        assert_eq!(AllowanceProfile::parse("0 28000"), None);
+        assert_eq!(AllowanceProfile::parse("24 -28000"), None);
+        assert_eq!(AllowanceProfile::parse("24 10000001"), None);
+        assert_eq!(
+            AllowanceProfile::parse("24 99999999999999999999999999"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rules_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.json");
+        std::fs::write(
+            &path,
+            r#"{ "maximum_rent": "932.93", "young_age_limit": 21 }"#,
+        )
+        .unwrap();
+        let rules = AllowanceRules::from_file(&path).unwrap();
+        assert_eq!(rules.maximum_rent, Decimal::new(93293, 2));
+        assert_eq!(rules.young_age_limit, 21);
+        assert_eq!(rules.capping_limit, AllowanceRules::default().capping_limit);
+        assert!(AllowanceRules::from_file(dir.path().join("missing.json")).is_err());
+    }
+
+    #[test]
+    fn test_base_rent() {
+        let rules = AllowanceRules::default();
+        assert_eq!(
+            rules.base_rent(Decimal::new(15_000, 0)),
+            rules.minimum_base_rent
+        );
+        assert_eq!(
+            rules.base_rent(Decimal::new(30_000, 0)),
+            Decimal::new(38821, 2)
+        );
+    }
+
+    #[test]
+    fn test_allowance() {
+        let rules = AllowanceRules::default();
+        // 477.20 - 238.21 fully and 65% of 598.12 - 477.20 partly compensated.
+        assert_eq!(
+            rules.allowance(&profile(25, 15_000), Decimal::new(59812, 2)),
+            Ok(Decimal::new(31759, 2))
+        );
+        assert_eq!(
+            rules.allowance(&profile(25, 15_000), Decimal::new(95000, 2)),
+            Err(Ineligibility::RentTooHigh {
+                rent: Decimal::new(95000, 2),
+                limit: rules.maximum_rent
+            })
+        );
+        assert!(matches!(
+            rules.allowance(&profile(21, 15_000), Decimal::new(59812, 2)),
+            Err(Ineligibility::RentTooHigh { .. })
+        ));
+        assert!(matches!(
+            rules.allowance(&profile(25, 80_000), Decimal::new(59812, 2)),
+            Err(Ineligibility::RentBelowBaseRent { .. })
+        ));
+        let rules = AllowanceRules {
+            maximum_yearly_income: Some(Decimal::new(40_000, 0)),
+            ..rules
+        };
+        assert!(matches!(
+            rules.allowance(&profile(25, 45_000), Decimal::new(59812, 2)),
+            Err(Ineligibility::IncomeTooHigh { .. })
+        ));
+    }
+}
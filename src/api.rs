@@ -1,37 +1,139 @@
-use std::{collections::HashMap, time::Duration};
+use std::collections::HashMap;
 
-use chrono::Datelike;
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
 
-fn get_graphql_query(city_id: CityId) -> String {
-    format!(
-        r#"{{ "operationName": "GetCategories", "variables": {{ "currentPage": 1, "filters": {{ "available_to_book": {{ "eq": "179" }}, "category_uid": {{ "eq": "Nw==" }}, "city": {{ "eq": "{}" }} }}, "pageSize": 100, "sort": {{ "available_startdate": "ASC" }} }}, "query": "query GetCategories($pageSize: Int!, $currentPage: Int!, $filters: ProductAttributeFilterInput!, $sort: ProductAttributeSortInput) {{ products( pageSize: $pageSize, currentPage: $currentPage, filter: $filters, sort: $sort ) {{ ...ProductsFragment, __typename }} }} fragment ProductsFragment on Products {{ sort_fields {{ options {{ label, value, __typename }}, __typename }}, aggregations {{ label, count, attribute_code, options {{ label, count, value, __typename }}, position, __typename }}, items {{ name, sku, city, url_key, available_to_book, available_startdate, next_contract_startdate, current_lottery_subscribers, building_name, finishing, living_area, no_of_rooms, resident_type, offer_text_two, offer_text, maximum_number_of_persons, type_of_contract, price_analysis_text, allowance_price, floor, basic_rent, lumpsum_service_charge, inventory, caretaker_costs, cleaning_common_areas, energy_common_areas, energy_label, minimum_stay, allowance_price, price_range {{ minimum_price {{ regular_price {{ value, currency, __typename }}, final_price {{ value, currency, __typename }}, __typename }}, maximum_price {{ regular_price {{ value, currency, __typename }}, final_price {{ value, currency, __typename }}, __typename }}, __typename }} , __typename }}, total_count, __typename }}" }}"#,
-        city_id.0
-    )
-}
+pub use account::{AuthenticatedClient, Customer, LotteryRegistration, Reservation};
+pub use client::{Holland2StayClient, Holland2StayClientBuilder};
+pub use details::{MediaImage, ResidenceDetails};
+pub(crate) use retry::retry_after;
+pub use retry::{RateLimiter, RetryPolicy};
+pub use search_query::{
+    Availability, AvailabilityRegistry, Range, SearchQuery, SortDirection, SortField,
+};
+pub use stats::{AttributeCounts, MarketStats, OptionCount, PriceDistribution};
+
+mod account;
+mod client;
+mod details;
+mod retry;
+mod search_query;
+mod stats;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Debug,
+    derive_more::Display,
+    derive_more::FromStr,
+)]
 pub struct CityId(u64);
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, derive_more::Display, derive_more::FromStr)]
-pub enum City {
-    Delft,
-    Eindhoven,
-    DenHaag,
-    Zoetermeer,
-    Rijswijk,
-    Rotterdam,
+/// The stock keeping unit holland2stay identifies a listing by. It stays the same when the price,
+/// start date or availability of the listing change.
+#[derive(
+    Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, derive_more::Display, derive_more::From,
+)]
+pub struct Sku(String);
+
+impl Sku {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A city as known by the holland2stay api. Two cities are the same if they have the same id.
+#[derive(Clone, Debug, derive_more::Display)]
+#[display("{name}")]
+pub struct City {
+    pub id: CityId,
+    pub name: String,
+}
+
+impl PartialEq for City {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for City {}
+
+impl std::hash::Hash for City {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
 }
 
 impl City {
-    pub fn id(&self) -> CityId {
-        match self {
-            City::Delft => CityId(26),
-            City::Eindhoven => CityId(29),
-            City::DenHaag => CityId(90),
-            City::Zoetermeer => CityId(6088),
-            City::Rijswijk => CityId(6224),
-            City::Rotterdam => CityId(25),
+    /// Case and whitespace insensitive name comparison, so that "den haag" matches "Den Haag".
+    pub fn matches(&self, name: &str) -> bool {
+        fn normalize(name: &str) -> String {
+            name.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect()
         }
+        normalize(&self.name) == normalize(name)
+    }
+}
+
+#[derive(Debug)]
+pub struct CityEntry {
+    pub city: City,
+    pub listing_count: u64,
+}
+
+/// The cities holland2stay has residences in, built from the `city` aggregation.
+#[derive(Default, Debug)]
+pub struct CityRegistry {
+    entries: Vec<CityEntry>,
+}
+
+impl CityRegistry {
+    fn from_aggregations(
+        aggregations: Vec<api_response::Aggregation>,
+    ) -> Result<Self, Holland2StayError> {
+        let city_aggregation = aggregations
+            .into_iter()
+            .find(|aggregation| aggregation.attribute_code == "city")
+            .ok_or(Holland2StayError::MissingCityAggregation)?;
+        let mut entries = city_aggregation
+            .options
+            .into_iter()
+            .map(|option| {
+                Ok(CityEntry {
+                    city: City {
+                        id: option.value.parse()?,
+                        name: option.label,
+                    },
+                    listing_count: option.count.unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>, Holland2StayError>>()?;
+        entries.sort_by(|a, b| a.city.name.cmp(&b.city.name));
+        Ok(CityRegistry { entries })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&City> {
+        self.entries
+            .iter()
+            .map(|entry| &entry.city)
+            .find(|city| city.matches(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CityEntry> {
+        self.entries.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 }
 
@@ -43,14 +145,196 @@ pub enum Holland2StayError {
     #[error("Conversion error: {0}")]
     ConversionError(String),
 
+    #[error("The response does not contain the city aggregation")]
+    MissingCityAggregation,
+
     #[error(transparent)]
     SerdeJsonError(#[from] serde_json::Error),
 
     #[error(transparent)]
-    FromStrError(#[from] derive_more::FromStrError),
+    ParseIntError(#[from] std::num::ParseIntError),
+
+    #[error("There is no listing with sku {0}")]
+    ListingNotFound(Sku),
+
+    #[error("The holland2stay api returned errors: {}", itertools::join(.0, "; "))]
+    GraphQl(Vec<GraphQlError>),
+
+    #[error("The holland2stay session expired and there are no credentials to log in again")]
+    SessionExpired,
+
+    #[error(transparent)]
+    Auth(#[from] crate::auth::AuthError),
+}
+
+impl Holland2StayError {
+    /// Whether the api rejected the bearer token, usually because the session expired.
+    pub fn is_unauthorized(&self) -> bool {
+        match self {
+            Holland2StayError::ReqwestError(err) => {
+                err.status() == Some(reqwest::StatusCode::UNAUTHORIZED)
+            }
+            Holland2StayError::GraphQl(errors) => errors.iter().any(|error| {
+                error
+                    .extensions
+                    .as_ref()
+                    .and_then(|extensions| extensions.get("category"))
+                    .is_some_and(|category| category == "graphql-authorization")
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// A segment of the path of the field a graphql error occurred in.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, derive_more::Display)]
+#[serde(untagged)]
+pub enum PathSegment {
+    #[display("{_0}")]
+    Field(String),
+    #[display("{_0}")]
+    Index(u64),
+}
+
+/// An entry of the `errors` array of a graphql response.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct GraphQlError {
+    pub message: String,
+    #[serde(default)]
+    pub path: Option<Vec<PathSegment>>,
+    /// Server specific details, such as the error category magento puts here.
+    #[serde(default)]
+    pub extensions: Option<serde_json::Value>,
+}
+
+impl std::fmt::Display for GraphQlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(path) = &self.path {
+            write!(f, " at {}", itertools::join(path, "."))?;
+        }
+        if let Some(extensions) = &self.extensions {
+            write!(f, " ({})", extensions)?;
+        }
+        Ok(())
+    }
 }
 
-fn is_some_or_unknown_str<T: ToString>(option: &Option<T>) -> String {
+#[derive(Clone, Debug, Hash, PartialEq, Eq, derive_more::Display)]
+#[display("{amount:.2} {currency}")]
+pub struct Money {
+    pub amount: Decimal,
+    pub currency: String,
+}
+
+/// The monthly costs that make up the rent of a house.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct CostBreakdown {
+    pub basic_rent: Option<Decimal>,
+    pub service_charge: Option<Decimal>,
+    /// The fee for the furniture of furnished houses.
+    pub inventory: Option<Decimal>,
+    pub caretaker_costs: Option<Decimal>,
+    pub cleaning_common_areas: Option<Decimal>,
+    pub energy_common_areas: Option<Decimal>,
+}
+
+impl CostBreakdown {
+    /// The known costs with a description of each, in the order holland2stay lists them.
+    pub fn items(&self) -> Vec<(&'static str, Decimal)> {
+        [
+            ("basic rent", self.basic_rent),
+            ("service charge", self.service_charge),
+            ("inventory", self.inventory),
+            ("caretaker", self.caretaker_costs),
+            ("cleaning common areas", self.cleaning_common_areas),
+            ("energy common areas", self.energy_common_areas),
+        ]
+        .into_iter()
+        .filter_map(|(description, amount)| Some((description, amount?)))
+        .collect()
+    }
+
+    /// The sum of the known costs, or `None` if none of them are known.
+    pub fn total(&self) -> Option<Decimal> {
+        let items = self.items();
+        (!items.is_empty()).then(|| items.iter().map(|(_, amount)| amount).sum())
+    }
+}
+
+impl std::fmt::Display for CostBreakdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(total) = self.total() else {
+            return write!(f, "unknown");
+        };
+        let items = itertools::join(
+            self.items()
+                .iter()
+                .map(|(description, amount)| format!("{amount:.2} {description}")),
+            " + ",
+        );
+        write!(f, "{items} = {total:.2}")
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, derive_more::Display)]
+pub enum ContractType {
+    #[display("indefinite")]
+    Indefinite,
+    /// A fixed term contract, with the label holland2stay uses to describe its duration.
+    #[display("{_0}")]
+    Temporary(String),
+    #[display("{_0}")]
+    Other(String),
+}
+
+impl ContractType {
+    /// Classifies the `type_of_contract` aggregation labels, "Indefinite" and
+    /// "Temporary - <duration>". Any other label is kept as is in [`ContractType::Other`].
+    fn from_label(label: &str) -> Self {
+        let trimmed = label.trim();
+        if trimmed.eq_ignore_ascii_case("indefinite") {
+            ContractType::Indefinite
+        } else if trimmed
+            .split_once('-')
+            .is_some_and(|(kind, _)| kind.trim().eq_ignore_ascii_case("temporary"))
+        {
+            ContractType::Temporary(label.to_string())
+        } else {
+            ContractType::Other(label.to_string())
+        }
+    }
+}
+
+/// Parses floor labels such as "3" or "Ground floor".
+fn parse_floor(label: &str) -> Option<i32> {
+    let label = label.trim();
+    if label.to_lowercase().starts_with("ground") {
+        return Some(0);
+    }
+    label.parse().ok()
+}
+
+/// Parses minimum stays such as "6", "6 months" or "1 year" into a number of months.
+fn parse_minimum_stay_months(minimum_stay: &str) -> Option<u32> {
+    let mut words = minimum_stay.split_whitespace();
+    let amount: u32 = words.next()?.parse().ok()?;
+    match words.next().map(str::to_lowercase) {
+        Some(unit) if unit.starts_with("year") => amount.checked_mul(12),
+        Some(unit) if unit.starts_with("month") => Some(amount),
+        None => Some(amount),
+        Some(_) => None,
+    }
+}
+
+/// How dates are shown to users, e.g. "5 March 2025".
+pub(crate) const DATE_FORMAT: &str = "%-d %B %Y";
+
+pub(crate) fn format_date(date: NaiveDate) -> String {
+    date.format(DATE_FORMAT).to_string()
+}
+
+pub(crate) fn is_some_or_unknown_str<T: ToString>(option: &Option<T>) -> String {
     if let Some(t) = option {
         t.to_string()
     } else {
@@ -58,44 +342,144 @@ fn is_some_or_unknown_str<T: ToString>(option: &Option<T>) -> String {
     }
 }
 
-#[derive(derive_new::new, derive_more::Display, Hash, PartialEq, Eq)]
+/// A snapshot of a listing. Listings are identified by their `sku`: two snapshots of the same
+/// listing can differ in any other field.
+#[derive(Clone, Debug, derive_more::Display, PartialEq, Eq)]
 #[display(
-    "{}: {} size: {} m2, floor: {}, minimum_stay: {}, price: {} euros, start_date: {}, contract_duration: {}, link: {}",
+    "{}: {} rooms: {}, finishing: {}, size: {} m2, floor: {}, minimum_stay: {}, price: {}, monthly_costs: {}, availability: {}, start_date: {}, contract_type: {}, link: {}",
     city,
     name,
-    is_some_or_unknown_str(size_meter_squared),
+    is_some_or_unknown_str(rooms),
+    is_some_or_unknown_str(finishing),
+    is_some_or_unknown_str(living_area),
     is_some_or_unknown_str(floor),
-    is_some_or_unknown_str(minimum_stay),
+    is_some_or_unknown_str(&minimum_stay_months.map(|months| format!("{months} months"))),
     is_some_or_unknown_str(price),
-    is_some_or_unknown_str(start_date),
-    is_some_or_unknown_str(contract_duration),
+    costs,
+    is_some_or_unknown_str(availability),
+    is_some_or_unknown_str(&start_date.map(format_date)),
+    is_some_or_unknown_str(contract_type),
     is_some_or_unknown_str(url)
 )]
 pub struct House {
     pub name: String,
+    pub sku: Sku,
     pub url: Option<reqwest::Url>,
     pub city: City,
-    pub size_meter_squared: Option<String>,
-    pub floor: Option<String>,
-    pub minimum_stay: Option<String>,
-    pub price: Option<String>,
-    pub start_date: Option<String>,
-    pub contract_duration: Option<String>,
+    pub building_name: Option<String>,
+    /// Living area in square meters.
+    pub living_area: Option<Decimal>,
+    pub rooms: Option<String>,
+    pub finishing: Option<String>,
+    pub resident_type: Option<String>,
+    pub maximum_number_of_persons: Option<String>,
+    pub energy_label: Option<String>,
+    pub floor: Option<i32>,
+    pub minimum_stay_months: Option<u32>,
+    pub price: Option<Money>,
+    pub costs: CostBreakdown,
+    /// The part of the rent that counts towards rent allowance (huurtoeslag).
+    pub allowance_price: Option<Decimal>,
+    pub price_analysis_text: Option<String>,
+    /// Whether the house can be booked directly or through a lottery.
+    pub availability: Option<Availability>,
+    pub available_from: Option<NaiveDate>,
+    pub start_date: Option<NaiveDate>,
+    pub current_lottery_subscribers: Option<u32>,
+    pub contract_type: Option<ContractType>,
+    pub offer_text: Option<String>,
+    pub offer_text_two: Option<String>,
 }
 
-mod api_house {
+/// The shape of the responses of the holland2stay graphql api, deserialized in one pass. Fields
+/// whose json type varies between listings are deserialized to their string form and parsed by
+/// [`FieldParser`].
+mod api_response {
     use std::collections::HashMap;
 
+    use rust_decimal::Decimal;
+    use serde::de::{IgnoredAny, MapAccess, SeqAccess, Visitor};
+
+    use super::search_query::SortField;
+
+    #[derive(serde::Deserialize)]
+    pub struct GraphQlResponse<T> {
+        pub data: Option<T>,
+        pub errors: Option<Vec<serde_json::Value>>,
+    }
+
+    /// Only the errors of a response, for when its data does not have the expected shape.
+    #[derive(serde::Deserialize)]
+    pub struct GraphQlErrors {
+        pub errors: Option<Vec<serde_json::Value>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct ProductsData<I> {
+        pub products: Option<Products<I>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct Products<I> {
+        pub items: Option<Vec<I>>,
+        pub aggregations: Option<Vec<Aggregation>>,
+        pub sort_fields: Option<SortFields>,
+        pub total_count: Option<u64>,
+    }
+
     #[derive(serde::Deserialize)]
     pub struct ApiHouse {
-        pub name: String,
-        pub url_key: String,
+        pub name: Option<String>,
+        pub sku: Option<String>,
+        pub url_key: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub city: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub building_name: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
         pub living_area: Option<String>,
-        pub floor: Option<serde_json::Value>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub no_of_rooms: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub finishing: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub resident_type: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub maximum_number_of_persons: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub energy_label: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub floor: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
         pub minimum_stay: Option<String>,
         pub price_range: Option<PriceRange>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub basic_rent: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub lumpsum_service_charge: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub inventory: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub caretaker_costs: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub cleaning_common_areas: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub energy_common_areas: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub allowance_price: Option<String>,
+        pub price_analysis_text: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub available_to_book: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub available_startdate: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
         pub next_contract_startdate: Option<String>,
-        pub type_of_contract: Option<serde_json::Value>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub current_lottery_subscribers: Option<String>,
+        #[serde(default, deserialize_with = "optional_string_or_number")]
+        pub type_of_contract: Option<String>,
+        pub offer_text: Option<String>,
+        pub offer_text_two: Option<String>,
     }
 
     #[derive(serde::Deserialize)]
@@ -110,208 +494,670 @@ mod api_house {
 
     #[derive(serde::Deserialize)]
     pub struct FinalPrice {
-        pub value: Option<f64>,
+        pub value: Option<Decimal>,
+        pub currency: Option<String>,
     }
 
     #[derive(serde::Deserialize)]
     pub struct Aggregation {
         pub attribute_code: AttributeCode,
+        pub label: Option<Label>,
         pub options: Vec<AttributeOption>,
     }
 
     #[derive(serde::Deserialize)]
     pub struct AttributeOption {
         pub label: Label,
+        #[serde(deserialize_with = "string_or_number")]
         pub value: Value,
+        pub count: Option<u64>,
     }
+
+    #[derive(serde::Deserialize)]
+    pub struct SortFields {
+        pub options: Vec<SortField>,
+    }
+
     type AttributeCode = String;
     type Label = String;
     type Value = String;
 
     pub type Aggregations = HashMap<AttributeCode, HashMap<Value, Label>>;
+
+    fn string_or_number<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<String, D::Error> {
+        optional_string_or_number(deserializer)?
+            .ok_or_else(|| serde::de::Error::custom("expected a string or number"))
+    }
+
+    /// A string, number or boolean in its string form, and `None` for null, arrays and objects.
+    fn optional_string_or_number<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<String>, D::Error> {
+        deserializer.deserialize_any(OptionalStringVisitor)
+    }
+
+    struct OptionalStringVisitor;
+
+    impl<'de> Visitor<'de> for OptionalStringVisitor {
+        type Value = Option<String>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a string, number or boolean")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
+            Ok(Some(value))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E> {
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E> {
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E> {
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_bool<E>(self, value: bool) -> Result<Self::Value, E> {
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_none<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: serde::Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            while seq.next_element::<IgnoredAny>()?.is_some() {}
+            Ok(None)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+            Ok(None)
+        }
+    }
 }
 
-trait ToRustString {
-    fn to_rust_string(&self) -> Option<String>;
+fn conversion_error() -> Holland2StayError {
+    Holland2StayError::ConversionError(
+        "Could not convert json response into list of available houses".to_string(),
+    )
 }
 
-impl ToRustString for serde_json::Value {
-    fn to_rust_string(&self) -> Option<String> {
-        match self {
-            serde_json::Value::String(s) => Some(s.clone()),
-            serde_json::Value::Number(n) => Some(n.to_string()),
-            serde_json::Value::Bool(b) => Some(b.to_string()),
-            serde_json::Value::Null => None,
-            _ => None,
+/// Turns a non-empty `errors` array into [`Holland2StayError::GraphQl`].
+fn graphql_error(errors: Option<Vec<serde_json::Value>>) -> Option<Holland2StayError> {
+    let errors = errors.filter(|errors| !errors.is_empty())?;
+    let errors = errors
+        .into_iter()
+        .map(|error| {
+            // Deserialized from a reference, so the error is still around for the fallback.
+            serde::Deserialize::deserialize(&error).unwrap_or_else(|_| GraphQlError {
+                message: error.to_string(),
+                path: None,
+                extensions: None,
+            })
+        })
+        .collect();
+    Some(Holland2StayError::GraphQl(errors))
+}
+
+/// Deserializes the `data` of a graphql response, or fails with the errors the api returned.
+fn parse_graphql_response<T: serde::de::DeserializeOwned>(
+    body: &[u8],
+) -> Result<T, Holland2StayError> {
+    let response: api_response::GraphQlResponse<T> = match serde_json::from_slice(body) {
+        Ok(response) => response,
+        Err(err) => {
+            let errors = serde_json::from_slice::<api_response::GraphQlErrors>(body)
+                .ok()
+                .and_then(|response| response.errors);
+            return Err(graphql_error(errors).unwrap_or(err.into()));
         }
+    };
+    if let Some(err) = graphql_error(response.errors) {
+        return Err(err);
     }
+    response.data.ok_or_else(conversion_error)
 }
 
-pub async fn query_houses_in_city(city: City) -> Result<Vec<House>, Holland2StayError> {
-    let url = reqwest::Url::parse("https://api.holland2stay.com/graphql/")
-        .expect("could not parse holland2stay api url");
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(15))
-        .build()?;
-    let mut response = client
-        .post(url)
-        .header("User-Agent", "Mozilla/5.0")
-        .header("Content-Type", "application/json")
-        .body(get_graphql_query(city.id()))
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<serde_json::Value>()
-        .await?;
-
-    let conversion_error = || {
-        Holland2StayError::ConversionError(
-            "Could not convert json response into list of available houses".to_string(),
-        )
-    };
+fn get_products<I>(
+    data: api_response::ProductsData<I>,
+) -> Result<api_response::Products<I>, Holland2StayError> {
+    data.products.ok_or_else(conversion_error)
+}
 
-    let products = response
-        .get_mut("data")
-        .ok_or_else(conversion_error)?
-        .get_mut("products")
-        .ok_or_else(conversion_error)?;
-    let mut aggregations_map: api_house::Aggregations = HashMap::new();
-    {
-        if let Some(aggregations) = (|| -> Option<Vec<api_house::Aggregation>> {
-            Some(
-                products
-                    .get_mut("aggregations")?
-                    .as_array_mut()?
-                    .into_iter()
-                    .map(|value| serde_json::from_value(value.take()))
-                    .collect::<Result<_, _>>()
-                    .ok()?,
-            )
-        })() {
-            for aggregation in aggregations {
-                let mut label_map = HashMap::new();
-                for option in aggregation.options {
-                    label_map.insert(option.value, option.label);
-                }
-                aggregations_map.insert(aggregation.attribute_code, label_map);
+/// Parses a products response of the api into the houses in `cities`, as the client does for
+/// every page it receives.
+pub fn parse_products_response(
+    body: &[u8],
+    cities: &[City],
+    residences_url: &reqwest::Url,
+) -> Result<Listings, Holland2StayError> {
+    let products = get_products(parse_graphql_response(body)?)?;
+    parse_houses(products, cities, residences_url)
+}
+
+/// A problem found while parsing a listing. A listing with an invalid field is still returned,
+/// without that field, while an invalid listing is skipped.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ListingWarning {
+    #[error("Skipped listing {sku}: {reason}")]
+    InvalidListing { sku: String, reason: String },
+
+    #[error("Could not parse {field} {value:?} of listing {sku}: {reason}")]
+    InvalidField {
+        sku: String,
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+/// The houses returned by a query, along with the problems found while parsing them.
+#[derive(Debug, Default)]
+pub struct Listings {
+    pub houses: Vec<House>,
+    pub warnings: Vec<ListingWarning>,
+    /// The number of listings per option of each attribute, over all pages of the query.
+    pub aggregations: Vec<AttributeCounts>,
+}
+
+impl Listings {
+    fn append(&mut self, mut other: Listings) {
+        self.houses.append(&mut other.houses);
+        self.warnings.append(&mut other.warnings);
+        if self.aggregations.is_empty() {
+            self.aggregations = other.aggregations;
+        }
+    }
+
+    /// Splits the listings by the city of each house. Warnings go with the house they are about,
+    /// warnings about skipped listings are returned separately. The aggregations cover all cities,
+    /// so every city gets a copy of them.
+    fn split_by_city(self, cities: &[City]) -> (HashMap<City, Listings>, Vec<ListingWarning>) {
+        let mut by_city: HashMap<City, Listings> = cities
+            .iter()
+            .map(|city| (city.clone(), Listings::default()))
+            .collect();
+        let city_of_sku: HashMap<String, City> = self
+            .houses
+            .iter()
+            .map(|house| (house.sku.0.clone(), house.city.clone()))
+            .collect();
+        let mut unassigned = Vec::new();
+        for warning in self.warnings {
+            let sku = match &warning {
+                ListingWarning::InvalidListing { sku, .. }
+                | ListingWarning::InvalidField { sku, .. } => sku,
+            };
+            match city_of_sku.get(sku) {
+                Some(city) => by_city
+                    .entry(city.clone())
+                    .or_default()
+                    .warnings
+                    .push(warning),
+                None => unassigned.push(warning),
             }
         }
+        for house in self.houses {
+            by_city
+                .entry(house.city.clone())
+                .or_default()
+                .houses
+                .push(house);
+        }
+        for listings in by_city.values_mut() {
+            listings.aggregations = self.aggregations.clone();
+        }
+        (by_city, unassigned)
     }
+}
 
-    let api_houses: Vec<api_house::ApiHouse> = products
-        .get_mut("items")
-        .ok_or_else(conversion_error)?
-        .as_array_mut()
-        .ok_or_else(conversion_error)?
-        .into_iter()
-        .map(|v| serde_json::from_value(v.take()))
-        .collect::<Result<_, _>>()?;
-
-    let mut houses = Vec::new();
-    for api_house in api_houses {
-        let floor = || -> Option<String> {
-            Some(
-                aggregations_map
-                    .get("floor")?
-                    .get(&api_house.floor?.to_rust_string()?)?
-                    .clone(),
-            )
-        }();
-        let contract_duration = || -> Option<String> {
-            Some(
-                aggregations_map
-                    .get("type_of_contract")?
-                    .get(&api_house.type_of_contract?.to_rust_string()?)?
-                    .clone(),
-            )
-        }();
-        let price = || -> Option<String> {
-            Some(
-                api_house
-                    .price_range?
-                    .maximum_price?
-                    .final_price?
-                    .value?
-                    .to_string(),
-            )
-        }();
-        let start_date = || -> Option<String> {
-            let naive_dt = chrono::NaiveDateTime::parse_from_str(
-                &api_house.next_contract_startdate?,
-                "%Y-%m-%d %H:%M:%S",
-            )
-            .expect("Failed to parse datetime format");
-            let day = naive_dt.day();
-            let month = naive_dt.format("%B"); // Full month name
-            let year = naive_dt.year();
-
-            Some(format!("{} {} {}", day, month, year))
-        }();
-        let url = reqwest::Url::parse("https://holland2stay.com/residences/")
-            .expect("Could not parse residences url")
-            .join(&api_house.url_key)
-            .ok();
-
-        let house = House::new(
-            api_house.name,
-            url,
-            city,
-            api_house.living_area,
-            floor,
-            api_house.minimum_stay,
-            price,
-            start_date,
-            contract_duration,
-        );
-        houses.push(house);
+fn parse_date(date: &str) -> Result<NaiveDate, chrono::ParseError> {
+    chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").map(|naive_dt| naive_dt.date())
+}
+
+/// Parses the fields of a single listing, collecting a warning for every field that fails.
+struct FieldParser<'a> {
+    sku: &'a str,
+    aggregations_map: &'a api_response::Aggregations,
+    warnings: &'a mut Vec<ListingWarning>,
+}
+
+impl FieldParser<'_> {
+    fn parse<T, E: std::fmt::Display>(
+        &mut self,
+        field: &'static str,
+        value: Option<String>,
+        parse: impl FnOnce(&str) -> Result<T, E>,
+    ) -> Option<T> {
+        let value = value?;
+        match parse(&value) {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                self.warnings.push(ListingWarning::InvalidField {
+                    sku: self.sku.to_string(),
+                    field,
+                    value,
+                    reason: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// Resolves an option id through the aggregations and parses its label.
+    fn parse_label<T>(
+        &mut self,
+        attribute_code: &'static str,
+        value: Option<String>,
+        parse: impl FnOnce(&str) -> Option<T>,
+    ) -> Option<T> {
+        let aggregations_map = self.aggregations_map;
+        self.parse(attribute_code, value, |value| {
+            let label = aggregations_map
+                .get(attribute_code)
+                .and_then(|labels| labels.get(value))
+                .ok_or("unknown option")?;
+            parse(label).ok_or("unexpected label")
+        })
+    }
+
+    /// Select attributes are option ids that are resolved through the aggregations, but not
+    /// every attribute has an aggregation, in which case the raw value is the best we have.
+    fn label_or_value(&self, attribute_code: &str, value: Option<String>) -> Option<String> {
+        let value = value?;
+        Some(
+            self.aggregations_map
+                .get(attribute_code)
+                .and_then(|labels| labels.get(&value))
+                .cloned()
+                .unwrap_or(value),
+        )
+    }
+
+    fn decimal(&mut self, field: &'static str, value: Option<String>) -> Option<Decimal> {
+        self.parse(field, value, |value| value.trim().parse::<Decimal>())
     }
-    Ok(houses)
 }
 
-pub async fn query_houses_in_cities(
-    cities: impl Iterator<Item = &City>,
-) -> Result<Vec<House>, Holland2StayError> {
-    let future_houses = cities.map(async |&city| query_houses_in_city(city).await);
+fn attribute_counts(aggregation: api_response::Aggregation) -> AttributeCounts {
+    AttributeCounts {
+        label: aggregation
+            .label
+            .unwrap_or_else(|| aggregation.attribute_code.clone()),
+        attribute_code: aggregation.attribute_code,
+        options: aggregation
+            .options
+            .into_iter()
+            .map(|option| OptionCount {
+                value: option.value,
+                label: option.label,
+                count: option.count.unwrap_or_default(),
+            })
+            .collect(),
+    }
+}
 
-    futures::future::join_all(future_houses)
-        .await
+/// Parses the items of a products response into houses in one of `cities`. Items without a city
+/// are taken to be in the city queried for if there is only one.
+fn parse_houses(
+    products: api_response::Products<api_response::ApiHouse>,
+    cities: &[City],
+    residences_url: &reqwest::Url,
+) -> Result<Listings, Holland2StayError> {
+    let aggregations: Vec<AttributeCounts> = products
+        .aggregations
+        .unwrap_or_default()
         .into_iter()
-        .try_fold(
-            vec![],
-            |mut acc, houses| -> Result<Vec<House>, Holland2StayError> {
-                acc.append(&mut houses?);
-                Ok(acc)
+        .map(attribute_counts)
+        .collect();
+    let mut aggregations_map: api_response::Aggregations = HashMap::new();
+    for attribute in &aggregations {
+        let label_map = attribute
+            .options
+            .iter()
+            .map(|option| (option.value.clone(), option.label.clone()))
+            .collect();
+        aggregations_map.insert(attribute.attribute_code.clone(), label_map);
+    }
+
+    let mut listings = Listings {
+        aggregations,
+        ..Listings::default()
+    };
+    for mut api_house in products.items.ok_or_else(conversion_error)? {
+        let missing_field = [
+            ("sku", api_house.sku.is_none()),
+            ("name", api_house.name.is_none()),
+            ("url_key", api_house.url_key.is_none()),
+        ]
+        .into_iter()
+        .find_map(|(field, missing)| missing.then_some(field));
+        let (Some(sku), Some(name), Some(url_key), None) = (
+            api_house.sku.take(),
+            api_house.name.take(),
+            api_house.url_key.take(),
+            missing_field,
+        ) else {
+            listings.warnings.push(ListingWarning::InvalidListing {
+                sku: api_house.sku.unwrap_or_else(|| "unknown".to_string()),
+                reason: format!("missing {}", missing_field.unwrap_or_default()),
+            });
+            continue;
+        };
+        let city_id = api_house.city.take();
+        let city = match (&city_id, cities) {
+            (None, [city]) => city,
+            _ => match cities
+                .iter()
+                .find(|city| Some(city.id.to_string()) == city_id)
+            {
+                Some(city) => city,
+                None => {
+                    listings.warnings.push(ListingWarning::InvalidListing {
+                        sku,
+                        reason: format!("unexpected city {}", is_some_or_unknown_str(&city_id)),
+                    });
+                    continue;
+                }
             },
-        )
+        };
+        let mut fields = FieldParser {
+            sku: &sku,
+            aggregations_map: &aggregations_map,
+            warnings: &mut listings.warnings,
+        };
+
+        let floor = fields.parse_label("floor", api_house.floor, parse_floor);
+        let contract_type =
+            fields.parse_label("type_of_contract", api_house.type_of_contract, |label| {
+                Some(ContractType::from_label(label))
+            });
+        let final_price = api_house
+            .price_range
+            .and_then(|price_range| price_range.maximum_price)
+            .and_then(|maximum_price| maximum_price.final_price);
+        let price = final_price.and_then(|final_price| {
+            Some(Money {
+                amount: final_price.value?,
+                currency: final_price.currency.unwrap_or_else(|| "EUR".to_string()),
+            })
+        });
+        let url = residences_url.join(&url_key).ok();
+
+        let house = House {
+            name,
+            url,
+            city: city.clone(),
+            building_name: fields.label_or_value("building_name", api_house.building_name),
+            living_area: fields.decimal("living_area", api_house.living_area),
+            rooms: fields.label_or_value("no_of_rooms", api_house.no_of_rooms),
+            finishing: fields.label_or_value("finishing", api_house.finishing),
+            resident_type: fields.label_or_value("resident_type", api_house.resident_type),
+            maximum_number_of_persons: fields.label_or_value(
+                "maximum_number_of_persons",
+                api_house.maximum_number_of_persons,
+            ),
+            energy_label: fields.label_or_value("energy_label", api_house.energy_label),
+            floor,
+            minimum_stay_months: fields.parse("minimum_stay", api_house.minimum_stay, |value| {
+                parse_minimum_stay_months(value).ok_or("unexpected format")
+            }),
+            price,
+            costs: CostBreakdown {
+                basic_rent: fields.decimal("basic_rent", api_house.basic_rent),
+                service_charge: fields
+                    .decimal("lumpsum_service_charge", api_house.lumpsum_service_charge),
+                inventory: fields.decimal("inventory", api_house.inventory),
+                caretaker_costs: fields.decimal("caretaker_costs", api_house.caretaker_costs),
+                cleaning_common_areas: fields
+                    .decimal("cleaning_common_areas", api_house.cleaning_common_areas),
+                energy_common_areas: fields
+                    .decimal("energy_common_areas", api_house.energy_common_areas),
+            },
+            allowance_price: fields.decimal("allowance_price", api_house.allowance_price),
+            price_analysis_text: api_house.price_analysis_text,
+            availability: fields.parse_label(
+                "available_to_book",
+                api_house.available_to_book,
+                Availability::from_label,
+            ),
+            available_from: fields.parse(
+                "available_startdate",
+                api_house.available_startdate,
+                parse_date,
+            ),
+            start_date: fields.parse(
+                "next_contract_startdate",
+                api_house.next_contract_startdate,
+                parse_date,
+            ),
+            current_lottery_subscribers: fields.parse(
+                "current_lottery_subscribers",
+                api_house.current_lottery_subscribers,
+                |value| value.parse::<u32>(),
+            ),
+            contract_type,
+            offer_text: api_house.offer_text,
+            offer_text_two: api_house.offer_text_two,
+            sku: Sku(sku),
+        };
+        listings.houses.push(house);
+    }
+    Ok(listings)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_server::rotterdam;
+    use serde_json::json;
 
     #[test]
-    fn test_body() {
-        let body = get_graphql_query(City::Eindhoven.id());
-        println!("{}", body);
+    fn test_parse_typed_fields() {
+        assert_eq!(parse_floor("3"), Some(3));
+        assert_eq!(parse_floor("Ground floor"), Some(0));
+        assert_eq!(parse_floor("Attic"), None);
+        assert_eq!(parse_minimum_stay_months("6"), Some(6));
+        assert_eq!(parse_minimum_stay_months("1 year"), Some(12));
+        assert_eq!(parse_minimum_stay_months("3 months"), Some(3));
+        assert_eq!(parse_minimum_stay_months("4000000000 years"), None);
+        assert_eq!(
+            ContractType::from_label("Indefinite"),
+            ContractType::Indefinite
+        );
+        assert_eq!(
+            ContractType::from_label("Temporary - max. 2 years"),
+            ContractType::Temporary("Temporary - max. 2 years".to_string())
+        );
+        assert_eq!(
+            ContractType::from_label("Max. 12 months"),
+            ContractType::Other("Max. 12 months".to_string())
+        );
+        assert_eq!(
+            ContractType::from_label("Not indefinite"),
+            ContractType::Other("Not indefinite".to_string())
+        );
     }
 
-    #[tokio::test]
-    async fn test_query_houses_in_city() {
-        let houses = query_houses_in_city(City::Rotterdam).await.unwrap();
-        for house in houses {
-            println!("{}", house);
-        }
+    #[test]
+    fn test_parse_houses_resolves_labels() {
+        let products = serde_json::from_str(
+            r#"{
+                "aggregations": [
+                    { "attribute_code": "finishing", "options": [ { "label": "Furnished", "value": "5", "count": 1 } ] },
+                    { "attribute_code": "no_of_rooms", "options": [ { "label": "Studio", "value": "104", "count": 1 } ] },
+                    { "attribute_code": "floor", "options": [ { "label": "2", "value": "6", "count": 1 } ] },
+                    { "attribute_code": "available_to_book", "options": [ { "label": "Available in lottery", "value": "336", "count": 1 } ] }
+                ],
+                "items": [ {
+                    "name": "Kruisplein 1",
+                    "sku": "KP-1",
+                    "url_key": "kruisplein-1",
+                    "building_name": "Kruisplein",
+                    "finishing": 5,
+                    "no_of_rooms": "104",
+                    "floor": "6",
+                    "available_to_book": "336",
+                    "energy_label": "A",
+                    "basic_rent": 650.5,
+                    "lumpsum_service_charge": "45.25",
+                    "allowance_price": 700,
+                    "inventory": null,
+                    "caretaker_costs": [],
+                    "price_range": { "maximum_price": { "final_price": { "value": 731.5, "currency": "EUR" } } },
+                    "current_lottery_subscribers": "12",
+                    "available_startdate": "2025-03-05 00:00:00"
+                } ],
+                "total_count": 1
+            }"#,
+        )
+        .unwrap();
+        let houses = parse_houses(
+            products,
+            &[rotterdam()],
+            &reqwest::Url::parse(client::DEFAULT_RESIDENCES_URL).unwrap(),
+        )
+        .unwrap()
+        .houses;
+        let house = &houses[0];
+        assert_eq!(house.sku.as_str(), "KP-1");
+        assert_eq!(
+            house.url.as_ref().map(reqwest::Url::as_str),
+            Some("https://holland2stay.com/residences/kruisplein-1")
+        );
+        assert_eq!(house.building_name.as_deref(), Some("Kruisplein"));
+        assert_eq!(house.finishing.as_deref(), Some("Furnished"));
+        assert_eq!(house.rooms.as_deref(), Some("Studio"));
+        assert_eq!(house.energy_label.as_deref(), Some("A"));
+        assert_eq!(house.floor, Some(2));
+        assert_eq!(house.costs.basic_rent, Some(Decimal::new(6505, 1)));
+        assert_eq!(house.costs.service_charge, Some(Decimal::new(4525, 2)));
+        assert_eq!(house.costs.total(), Some(Decimal::new(69575, 2)));
+        assert_eq!(house.costs.inventory, None);
+        assert_eq!(house.costs.caretaker_costs, None);
+        assert_eq!(
+            house.price.as_ref().map(|price| price.amount),
+            Some(Decimal::new(7315, 1))
+        );
+        assert_eq!(house.availability, Some(Availability::Lottery));
+        assert_eq!(house.current_lottery_subscribers, Some(12));
+        assert_eq!(house.available_from, NaiveDate::from_ymd_opt(2025, 3, 5));
+    }
+
+    #[test]
+    fn test_parse_graphql_response() {
+        let parse = |response: serde_json::Value| {
+            parse_graphql_response::<api_response::ProductsData<serde_json::Value>>(
+                response.to_string().as_bytes(),
+            )
+        };
+        assert!(parse(json!({ "data": { "products": null }, "errors": [] })).is_ok());
+        assert!(matches!(
+            parse(json!({ "data": null })),
+            Err(Holland2StayError::ConversionError(_))
+        ));
+        assert!(matches!(
+            parse(json!({ "data": { "products": [] } })),
+            Err(Holland2StayError::SerdeJsonError(_))
+        ));
+        // The errors take precedence over data that does not have the expected shape.
+        let Err(error) = parse(json!({
+            "data": { "products": [] },
+            "errors": [
+                {
+                    "message": "Field \"foo\" is not defined by type \"ProductAttributeFilterInput\".",
+                    "locations": [{ "line": 1, "column": 2 }],
+                    "extensions": { "category": "graphql" },
+                },
+                {
+                    "message": "Internal server error",
+                    "path": ["products", "items", 0, "floor"],
+                },
+                "unexpected",
+            ],
+        })) else {
+            panic!("expected the errors of the response");
+        };
+        let Holland2StayError::GraphQl(errors) = &error else {
+            panic!("unexpected error {:?}", error);
+        };
+        assert_eq!(errors[0].path, None);
+        assert_eq!(errors[0].extensions, Some(json!({ "category": "graphql" })));
+        assert_eq!(
+            errors[1].path,
+            Some(vec![
+                PathSegment::Field("products".to_string()),
+                PathSegment::Field("items".to_string()),
+                PathSegment::Index(0),
+                PathSegment::Field("floor".to_string()),
+            ])
+        );
+        assert_eq!(errors[2].message, "\"unexpected\"");
+        assert_eq!(
+            error.to_string(),
+            "The holland2stay api returned errors: Field \"foo\" is not defined by type \"ProductAttributeFilterInput\". ({\"category\":\"graphql\"}); Internal server error at products.items.0.floor; \"unexpected\""
+        );
     }
 
-    #[tokio::test]
-    async fn test_query_houses_cities() {
-        let cities = query_houses_in_cities(
-            [City::Rotterdam, City::Eindhoven, City::DenHaag, City::Delft].iter(),
+    #[test]
+    fn test_cost_breakdown() {
+        assert_eq!(CostBreakdown::default().total(), None);
+        assert_eq!(CostBreakdown::default().to_string(), "unknown");
+        let costs = CostBreakdown {
+            basic_rent: Some(Decimal::new(59812, 2)),
+            service_charge: Some(Decimal::new(455, 1)),
+            inventory: Some(Decimal::new(60, 0)),
+            ..Default::default()
+        };
+        assert_eq!(costs.total(), Some(Decimal::new(70362, 2)));
+        assert_eq!(
+            costs.to_string(),
+            "598.12 basic rent + 45.50 service charge + 60.00 inventory = 703.62"
+        );
+    }
+
+    #[test]
+    fn test_city_registry_from_aggregations() {
+        let aggregations: Vec<api_response::Aggregation> = serde_json::from_str(
+            r#"[
+                { "attribute_code": "finishing", "options": [ { "label": "Furnished", "value": "5", "count": 3 } ] },
+                { "attribute_code": "city", "options": [
+                    { "label": "Rotterdam", "value": "25", "count": 12 },
+                    { "label": "Den Haag", "value": "90", "count": 4 }
+                ] }
+            ]"#,
         )
-        .await
         .unwrap();
-        for city in cities {
-            println!("{}", city);
-        }
+        let registry = CityRegistry::from_aggregations(aggregations).unwrap();
+        let names: Vec<&str> = registry
+            .iter()
+            .map(|entry| entry.city.name.as_str())
+            .collect();
+        assert_eq!(names, ["Den Haag", "Rotterdam"]);
+        assert_eq!(registry.find("denhaag").unwrap().id, CityId(90));
+        assert_eq!(registry.find("ROTTERDAM"), Some(&rotterdam()));
+        assert!(registry.find("Amsterdam").is_none());
     }
 }
@@ -0,0 +1,337 @@
+use chrono::{NaiveDate, Utc};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use super::{Holland2StayClient, Holland2StayError, Sku, conversion_error, parse_date};
+use crate::auth::Login;
+
+const CUSTOMER_QUERY: &str =
+    "query GetCustomer { customer { firstname, lastname, email, __typename } }";
+const RESERVATIONS_QUERY: &str = "query GetReservations { customer { reservations { items { reservation_id, sku, name, status, start_date, end_date, __typename }, __typename }, __typename } }";
+const LOTTERY_REGISTRATIONS_QUERY: &str = "query GetLotteryRegistrations { customer { lottery_registrations { items { sku, name, status, position, draw_date, __typename }, __typename }, __typename } }";
+
+/// The account a login belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Customer {
+    pub firstname: Option<String>,
+    pub lastname: Option<String>,
+    pub email: Option<String>,
+}
+
+/// A residence the customer booked or is in the process of booking.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    pub id: String,
+    pub sku: Sku,
+    pub name: Option<String>,
+    pub status: Option<String>,
+    pub start_date: Option<NaiveDate>,
+    pub end_date: Option<NaiveDate>,
+}
+
+/// A lottery the customer signed up for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LotteryRegistration {
+    pub sku: Sku,
+    pub name: Option<String>,
+    pub status: Option<String>,
+    /// The place the customer was drawn at, once the lottery is drawn.
+    pub position: Option<u32>,
+    pub draw_date: Option<NaiveDate>,
+}
+
+mod api_account {
+    #[derive(serde::Deserialize)]
+    pub struct CustomerData<C> {
+        pub customer: Option<C>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct ApiCustomer {
+        pub firstname: Option<String>,
+        pub lastname: Option<String>,
+        pub email: Option<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct Reservations {
+        pub reservations: Option<Items<ApiReservation>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct LotteryRegistrations {
+        pub lottery_registrations: Option<Items<ApiLotteryRegistration>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct Items<I> {
+        pub items: Option<Vec<I>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct ApiReservation {
+        pub reservation_id: String,
+        pub sku: String,
+        pub name: Option<String>,
+        pub status: Option<String>,
+        pub start_date: Option<String>,
+        pub end_date: Option<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct ApiLotteryRegistration {
+        pub sku: String,
+        pub name: Option<String>,
+        pub status: Option<String>,
+        pub position: Option<u32>,
+        pub draw_date: Option<String>,
+    }
+}
+
+fn to_graphql_body(operation_name: &str, query: &str) -> serde_json::Value {
+    json!({
+        "operationName": operation_name,
+        "variables": {},
+        "query": query,
+    })
+}
+
+fn parse_optional_date(date: Option<String>) -> Option<NaiveDate> {
+    parse_date(&date?).ok()
+}
+
+/// A client for the account-only parts of the holland2stay graphql api, which sends the bearer
+/// token of a [`Login`] along with every query.
+///
+/// The session is renewed shortly before it expires, and whenever the api rejects the token, by
+/// logging in again with the credentials of the login.
+pub struct AuthenticatedClient {
+    client: Holland2StayClient,
+    login: Mutex<Login>,
+}
+
+impl AuthenticatedClient {
+    pub(super) fn new(client: Holland2StayClient, login: Login) -> Self {
+        AuthenticatedClient {
+            client,
+            login: Mutex::new(login),
+        }
+    }
+
+    /// The client for the queries that do not need a login.
+    pub fn public(&self) -> &Holland2StayClient {
+        &self.client
+    }
+
+    /// The bearer token of the current session, renewing the session first if it is about to
+    /// expire.
+    pub async fn bearer_token(&self) -> Result<String, Holland2StayError> {
+        let mut login = self.login.lock().await;
+        if login.needs_refresh(Utc::now()) {
+            log::info!("The holland2stay session is about to expire, logging in again");
+            login.relogin(self.client.retry_policy()).await?;
+        }
+        Ok(login.bearer_token().to_string())
+    }
+
+    /// Logs in again unless another query already did since it was rejected with `stale_token`.
+    async fn renew_session(&self, stale_token: &str) -> Result<String, Holland2StayError> {
+        let mut login = self.login.lock().await;
+        if login.bearer_token() == stale_token {
+            log::info!("The holland2stay api rejected the session, logging in again");
+            login.relogin(self.client.retry_policy()).await?;
+        }
+        Ok(login.bearer_token().to_string())
+    }
+
+    /// Posts a query and returns the `customer` field of its data, which the api leaves empty if
+    /// the login is not authorized.
+    async fn query_customer_field<C: serde::de::DeserializeOwned>(
+        &self,
+        body: serde_json::Value,
+    ) -> Result<C, Holland2StayError> {
+        let bearer_token = self.bearer_token().await?;
+        let data: api_account::CustomerData<C> = match self
+            .client
+            .post_graphql_query_as(body.clone(), Some(&bearer_token))
+            .await
+        {
+            Err(err) if err.is_unauthorized() => {
+                let bearer_token = self.renew_session(&bearer_token).await?;
+                self.client
+                    .post_graphql_query_as(body, Some(&bearer_token))
+                    .await?
+            }
+            result => result?,
+        };
+        data.customer.ok_or_else(conversion_error)
+    }
+
+    pub async fn query_customer(&self) -> Result<Customer, Holland2StayError> {
+        let customer: api_account::ApiCustomer = self
+            .query_customer_field(to_graphql_body("GetCustomer", CUSTOMER_QUERY))
+            .await?;
+        Ok(Customer {
+            firstname: customer.firstname,
+            lastname: customer.lastname,
+            email: customer.email,
+        })
+    }
+
+    pub async fn query_reservations(&self) -> Result<Vec<Reservation>, Holland2StayError> {
+        let customer: api_account::Reservations = self
+            .query_customer_field(to_graphql_body("GetReservations", RESERVATIONS_QUERY))
+            .await?;
+        let items = customer
+            .reservations
+            .and_then(|reservations| reservations.items)
+            .unwrap_or_default();
+        Ok(items
+            .into_iter()
+            .map(|reservation| Reservation {
+                id: reservation.reservation_id,
+                sku: Sku(reservation.sku),
+                name: reservation.name,
+                status: reservation.status,
+                start_date: parse_optional_date(reservation.start_date),
+                end_date: parse_optional_date(reservation.end_date),
+            })
+            .collect())
+    }
+
+    pub async fn query_lottery_registrations(
+        &self,
+    ) -> Result<Vec<LotteryRegistration>, Holland2StayError> {
+        let customer: api_account::LotteryRegistrations = self
+            .query_customer_field(to_graphql_body(
+                "GetLotteryRegistrations",
+                LOTTERY_REGISTRATIONS_QUERY,
+            ))
+            .await?;
+        let items = customer
+            .lottery_registrations
+            .and_then(|registrations| registrations.items)
+            .unwrap_or_default();
+        Ok(items
+            .into_iter()
+            .map(|registration| LotteryRegistration {
+                sku: Sku(registration.sku),
+                name: registration.name,
+                status: registration.status,
+                position: registration.position,
+                draw_date: parse_optional_date(registration.draw_date),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Auth, login_holland2stay_at};
+    use crate::test_server::FakeHolland2Stay;
+    use wiremock::{
+        Mock, ResponseTemplate,
+        matchers::{body_partial_json, method},
+    };
+
+    async fn authenticated_client(server: &FakeHolland2Stay) -> AuthenticatedClient {
+        let login = login_holland2stay_at(
+            &server.base_url(),
+            &Auth::new("jane@example.com".to_string(), "hunter2".to_string()),
+        )
+        .await
+        .unwrap();
+        server.client_builder().build_authenticated(login).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query_customer() {
+        let server = FakeHolland2Stay::start().await;
+        let customer = authenticated_client(&server)
+            .await
+            .query_customer()
+            .await
+            .unwrap();
+        assert_eq!(customer.email.as_deref(), Some("jane@example.com"));
+        assert_eq!(customer.firstname.as_deref(), Some("Jane"));
+    }
+
+    #[tokio::test]
+    async fn test_query_reservations() {
+        let server = FakeHolland2Stay::start().await;
+        let reservations = authenticated_client(&server)
+            .await
+            .query_reservations()
+            .await
+            .unwrap();
+        assert_eq!(reservations.len(), 1);
+        assert_eq!(reservations[0].sku.as_str(), "RTD-KRP-12A");
+        assert_eq!(
+            reservations[0].start_date,
+            NaiveDate::from_ymd_opt(2025, 3, 5)
+        );
+        assert_eq!(reservations[0].end_date, None);
+    }
+
+    #[tokio::test]
+    async fn test_query_lottery_registrations() {
+        let server = FakeHolland2Stay::start().await;
+        let registrations = authenticated_client(&server)
+            .await
+            .query_lottery_registrations()
+            .await
+            .unwrap();
+        assert_eq!(registrations.len(), 2);
+        assert_eq!(registrations[0].position, Some(4));
+        assert_eq!(registrations[1].position, None);
+        assert_eq!(
+            registrations[1].draw_date,
+            NaiveDate::from_ymd_opt(2025, 4, 15)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_query() {
+        let server = FakeHolland2Stay::start().await;
+        let client = server
+            .client_builder()
+            .build_authenticated(Login::new(
+                crate::auth::build_client(),
+                "expired".to_string(),
+            ))
+            .unwrap();
+        // Without credentials there is no way to log in again.
+        let error = client.query_customer().await.unwrap_err();
+        assert!(matches!(error, Holland2StayError::SessionExpired));
+    }
+
+    #[tokio::test]
+    async fn test_logs_in_again_when_rejected() {
+        let server = FakeHolland2Stay::start().await;
+        let client = authenticated_client(&server).await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "operationName": "GetCustomer" })))
+            .respond_with(ResponseTemplate::new(401))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let customer = client.query_customer().await.unwrap();
+        assert_eq!(customer.email.as_deref(), Some("jane@example.com"));
+
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let logins = requests
+            .iter()
+            .filter(|request| request.url.path() == "/api/auth/callback/credentials")
+            .count();
+        assert_eq!(logins, 2);
+    }
+
+    #[tokio::test]
+    async fn test_public_queries() {
+        let server = FakeHolland2Stay::start().await;
+        let client = authenticated_client(&server).await;
+        assert!(!client.public().query_cities().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,941 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use reqwest::{
+    Url,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+
+use super::retry::{RateLimiter, RetryPolicy, retry_after};
+use super::{
+    AuthenticatedClient, AvailabilityRegistry, City, CityRegistry, Holland2StayError,
+    ListingWarning, Listings, ResidenceDetails, SearchQuery, Sku, SortField,
+    api_response::{Products, ProductsData},
+    conversion_error, details, get_products, parse_graphql_response, parse_houses,
+};
+
+pub const DEFAULT_API_URL: &str = "https://api.holland2stay.com/graphql/";
+pub const DEFAULT_RESIDENCES_URL: &str = "https://holland2stay.com/residences/";
+/// The default minimum time between two requests to the api.
+pub const DEFAULT_RATE_LIMIT: Duration = Duration::from_millis(250);
+
+pub struct Holland2StayClientBuilder {
+    api_url: Url,
+    residences_url: Url,
+    timeout: Duration,
+    user_agent: String,
+    headers: HeaderMap,
+    retry_policy: RetryPolicy,
+    rate_limit: Duration,
+}
+
+impl Default for Holland2StayClientBuilder {
+    fn default() -> Self {
+        Holland2StayClientBuilder {
+            api_url: Url::parse(DEFAULT_API_URL).expect("could not parse holland2stay api url"),
+            residences_url: Url::parse(DEFAULT_RESIDENCES_URL)
+                .expect("Could not parse residences url"),
+            timeout: Duration::from_secs(15),
+            user_agent: "Mozilla/5.0".to_string(),
+            headers: HeaderMap::new(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: DEFAULT_RATE_LIMIT,
+        }
+    }
+}
+
+impl Holland2StayClientBuilder {
+    /// The graphql endpoint, e.g. a local stand-in server.
+    pub fn api_url(mut self, api_url: Url) -> Self {
+        self.api_url = api_url;
+        self
+    }
+
+    /// The url house links are built from by joining their `url_key`.
+    pub fn residences_url(mut self, residences_url: Url) -> Self {
+        self.residences_url = residences_url;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// A header sent along with every request.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// The minimum time between two requests, shared by all clones of the client.
+    pub fn rate_limit(mut self, min_interval: Duration) -> Self {
+        self.rate_limit = min_interval;
+        self
+    }
+
+    /// Builds a client that makes its requests on behalf of the user of `login`.
+    pub fn build_authenticated(
+        self,
+        login: crate::auth::Login,
+    ) -> Result<AuthenticatedClient, Holland2StayError> {
+        Ok(self.build()?.authenticated(login))
+    }
+
+    pub fn build(self) -> Result<Holland2StayClient, Holland2StayError> {
+        let client = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .user_agent(self.user_agent)
+            .default_headers(self.headers)
+            .build()?;
+        Ok(Holland2StayClient {
+            client,
+            api_url: self.api_url,
+            residences_url: self.residences_url,
+            retry_policy: self.retry_policy,
+            rate_limiter: Arc::new(RateLimiter::new(self.rate_limit)),
+            availability_ids: Arc::default(),
+        })
+    }
+}
+
+/// A client for the public holland2stay graphql api.
+///
+/// Cloning is cheap and clones share the same connection pool and rate limiter, so a single client
+/// should be reused across polling cycles.
+#[derive(Clone)]
+pub struct Holland2StayClient {
+    client: reqwest::Client,
+    api_url: Url,
+    residences_url: Url,
+    retry_policy: RetryPolicy,
+    rate_limiter: Arc<RateLimiter>,
+    /// Fetched when first needed, and refreshed along with the cities.
+    availability_ids: Arc<tokio::sync::Mutex<Option<AvailabilityRegistry>>>,
+}
+
+impl Holland2StayClient {
+    pub fn new() -> Result<Self, Holland2StayError> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> Holland2StayClientBuilder {
+        Holland2StayClientBuilder::default()
+    }
+
+    /// A client that makes its requests on behalf of the user of `login`, sharing the connection
+    /// pool and rate limiter of this one.
+    pub fn authenticated(&self, login: crate::auth::Login) -> AuthenticatedClient {
+        AuthenticatedClient::new(self.clone(), login)
+    }
+
+    async fn post_graphql_query<T: serde::de::DeserializeOwned>(
+        &self,
+        body: serde_json::Value,
+    ) -> Result<T, Holland2StayError> {
+        self.post_graphql_query_as(body, None).await
+    }
+
+    pub(super) fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Posts a graphql query, authorized by `bearer_token` if there is one.
+    pub(super) async fn post_graphql_query_as<T: serde::de::DeserializeOwned>(
+        &self,
+        body: serde_json::Value,
+        bearer_token: Option<&str>,
+    ) -> Result<T, Holland2StayError> {
+        let mut retry = 0;
+        loop {
+            self.rate_limiter.acquire().await;
+            let mut request = self.client.post(self.api_url.clone()).json(&body);
+            if let Some(bearer_token) = bearer_token {
+                request = request.bearer_auth(bearer_token);
+            }
+            let result = request.send().await;
+            let backoff = match result {
+                Ok(response) if RetryPolicy::is_retryable_status(response.status()) => {
+                    let backoff = match retry_after(response.headers()) {
+                        Some(retry_after) if retry_after > self.retry_policy.max_backoff => None,
+                        Some(retry_after) => Some(retry_after),
+                        None => Some(self.retry_policy.backoff(retry)),
+                    };
+                    match backoff {
+                        Some(backoff) if retry < self.retry_policy.max_retries => {
+                            log::warn!(
+                                "holland2stay api responded with {}, retrying in {:?}",
+                                response.status(),
+                                backoff
+                            );
+                            backoff
+                        }
+                        _ => return Err(response.error_for_status().unwrap_err().into()),
+                    }
+                }
+                Ok(response) => {
+                    let body = response.error_for_status()?.bytes().await?;
+                    return parse_graphql_response(&body);
+                }
+                Err(error)
+                    if RetryPolicy::is_retryable_error(&error)
+                        && retry < self.retry_policy.max_retries =>
+                {
+                    let backoff = self.retry_policy.backoff(retry);
+                    log::warn!(
+                        "Request to holland2stay api failed: {}, retrying in {:?}",
+                        error,
+                        backoff
+                    );
+                    backoff
+                }
+                Err(error) => return Err(error.into()),
+            };
+            tokio::time::sleep(backoff).await;
+            retry += 1;
+        }
+    }
+
+    /// Fetches a single residence of any availability, for the aggregations and sort fields that
+    /// come with it.
+    async fn query_unfiltered(&self) -> Result<Products<serde::de::IgnoredAny>, Holland2StayError> {
+        let body = SearchQuery::default()
+            .availability([])
+            .page_size(1)
+            .to_graphql_body(1, &AvailabilityRegistry::default());
+        let response: ProductsData<serde::de::IgnoredAny> = self.post_graphql_query(body).await?;
+        get_products(response)
+    }
+
+    /// Fetches all the cities holland2stay has residences in, whether or not they can be booked
+    /// right now. The availability ids are refreshed along the way.
+    pub async fn query_cities(&self) -> Result<CityRegistry, Holland2StayError> {
+        let aggregations = self
+            .query_unfiltered()
+            .await?
+            .aggregations
+            .ok_or_else(conversion_error)?;
+        let availability_ids = AvailabilityRegistry::from_aggregations(&aggregations);
+        *self.availability_ids.lock().await =
+            Some(availability_ids).filter(AvailabilityRegistry::is_complete);
+        CityRegistry::from_aggregations(aggregations)
+    }
+
+    /// Fetches the `available_to_book` option ids [`SearchQuery::availability`] filters on.
+    pub async fn query_availability_ids(&self) -> Result<AvailabilityRegistry, Holland2StayError> {
+        let aggregations = self
+            .query_unfiltered()
+            .await?
+            .aggregations
+            .ok_or_else(conversion_error)?;
+        Ok(AvailabilityRegistry::from_aggregations(&aggregations))
+    }
+
+    /// The availability ids, fetched the first time they are needed. Ids without an option for
+    /// every availability are not kept, so that an option that comes back is picked up by the next
+    /// query rather than after the next refresh of the cities.
+    async fn availability_ids(&self) -> Result<AvailabilityRegistry, Holland2StayError> {
+        let mut availability_ids = self.availability_ids.lock().await;
+        if let Some(availability_ids) = availability_ids.as_ref() {
+            return Ok(availability_ids.clone());
+        }
+        let fetched = self.query_availability_ids().await?;
+        if fetched.is_complete() {
+            *availability_ids = Some(fetched.clone());
+        }
+        Ok(fetched)
+    }
+
+    /// Fetches the fields houses can be sorted by with [`SearchQuery::sort_by`].
+    pub async fn query_sort_fields(&self) -> Result<Vec<SortField>, Holland2StayError> {
+        let sort_fields = self
+            .query_unfiltered()
+            .await?
+            .sort_fields
+            .ok_or_else(conversion_error)?;
+        Ok(sort_fields.options)
+    }
+
+    /// Fetches every page of houses in `cities` matching `query`, ignoring the cities `query`
+    /// filters on.
+    async fn query_houses(
+        &self,
+        cities: &[City],
+        query: &SearchQuery,
+    ) -> Result<Listings, Holland2StayError> {
+        let query = query.clone().cities(cities.iter().map(|city| city.id));
+        let availability_ids = if query.filters_availability() {
+            self.availability_ids().await?
+        } else {
+            AvailabilityRegistry::default()
+        };
+        let mut listings = Listings::default();
+        if query.matches_nothing(&availability_ids) {
+            return Ok(listings);
+        }
+        let mut current_page = 1;
+        let total_count = loop {
+            let response = self
+                .post_graphql_query(query.to_graphql_body(current_page, &availability_ids))
+                .await?;
+            let products = get_products(response)?;
+            let total_count = products.total_count.ok_or_else(conversion_error)?;
+            let page = parse_houses(products, cities, &self.residences_url)?;
+            let is_last_page = (page.houses.is_empty() && page.warnings.is_empty())
+                || u64::from(current_page) * u64::from(query.page_size) >= total_count;
+            listings.append(page);
+            if is_last_page {
+                break total_count;
+            }
+            current_page += 1;
+        };
+        let received = listings.houses.len()
+            + listings
+                .warnings
+                .iter()
+                .filter(|warning| matches!(warning, ListingWarning::InvalidListing { .. }))
+                .count();
+        if received as u64 != total_count {
+            log::warn!(
+                "Received {} houses in {} but holland2stay reported a total count of {}",
+                received,
+                itertools::join(cities, ", "),
+                total_count
+            );
+        }
+        Ok(listings)
+    }
+
+    /// Fetches every page of houses in `city` matching `query`, ignoring the cities `query` filters on.
+    pub async fn query_houses_in_city(
+        &self,
+        city: &City,
+        query: &SearchQuery,
+    ) -> Result<Listings, Holland2StayError> {
+        self.query_houses(std::slice::from_ref(city), query).await
+    }
+
+    /// Fetches the description, photos and floor plan of the listing with `sku`.
+    pub async fn query_residence_details(
+        &self,
+        sku: &Sku,
+    ) -> Result<ResidenceDetails, Holland2StayError> {
+        let response: ProductsData<details::api_details::ApiProductDetails> = self
+            .post_graphql_query(details::to_graphql_body(sku))
+            .await?;
+        details::parse_details(get_products(response)?, sku)
+    }
+
+    /// Queries all cities at once and splits the houses by city. If that fails, every city is
+    /// queried on its own, so that a city that fails does not affect the others.
+    pub async fn query_houses_in_cities(
+        &self,
+        cities: impl Iterator<Item = &City>,
+        query: &SearchQuery,
+    ) -> HashMap<City, Result<Listings, Holland2StayError>> {
+        let mut cities: Vec<City> = cities.cloned().collect();
+        cities.sort_by_key(|city| city.id);
+        cities.dedup();
+        if cities.len() <= 1 {
+            return self.query_each_city(&cities, query).await;
+        }
+
+        match self.query_houses(&cities, query).await {
+            Ok(listings) => {
+                let (by_city, unassigned_warnings) = listings.split_by_city(&cities);
+                for warning in unassigned_warnings {
+                    log::warn!("{}", warning);
+                }
+                by_city
+                    .into_iter()
+                    .map(|(city, listings)| (city, Ok(listings)))
+                    .collect()
+            }
+            Err(err) => {
+                log::warn!(
+                    "Could not query houses in {} at once, querying them one by one: {}",
+                    itertools::join(&cities, ", "),
+                    err
+                );
+                self.query_each_city(&cities, query).await
+            }
+        }
+    }
+
+    /// Queries every city concurrently.
+    async fn query_each_city(
+        &self,
+        cities: &[City],
+        query: &SearchQuery,
+    ) -> HashMap<City, Result<Listings, Holland2StayError>> {
+        let future_houses = cities
+            .iter()
+            .map(async |city| (city.clone(), self.query_houses_in_city(city, query).await));
+
+        futures::future::join_all(future_houses)
+            .await
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Availability, ContractType};
+    use crate::test_server::{FakeHolland2Stay, delft, fixture, rotterdam};
+    use wiremock::{Mock, ResponseTemplate, matchers::method};
+
+    /// The bodies of the queries for houses, leaving out the lookup of the availability ids.
+    fn house_queries(requests: &[wiremock::Request]) -> Vec<serde_json::Value> {
+        requests
+            .iter()
+            .map(|request| request.body_json::<serde_json::Value>().unwrap())
+            .filter(|body| body["variables"]["filters"].get("city").is_some())
+            .collect()
+    }
+
+    #[test]
+    fn test_builder() {
+        let api_url = Url::parse("http://127.0.0.1:8080/graphql/").unwrap();
+        let client = Holland2StayClient::builder()
+            .api_url(api_url.clone())
+            .timeout(Duration::from_secs(1))
+            .user_agent("holland2stay-rs")
+            .header(
+                HeaderName::from_static("x-test"),
+                HeaderValue::from_static("1"),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(client.api_url, api_url);
+        assert_eq!(client.residences_url.as_str(), DEFAULT_RESIDENCES_URL);
+    }
+
+    #[tokio::test]
+    async fn test_query_cities() {
+        let server = FakeHolland2Stay::start().await;
+        let registry = server.client().query_cities().await.unwrap();
+        let cities: Vec<(String, u64)> = registry
+            .iter()
+            .map(|entry| (entry.city.name.clone(), entry.listing_count))
+            .collect();
+        assert_eq!(
+            cities,
+            [
+                ("Amsterdam".to_string(), 41),
+                ("Delft".to_string(), 12),
+                ("Den Haag".to_string(), 64),
+                ("Eindhoven".to_string(), 153),
+                ("Rotterdam".to_string(), 87),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_sort_fields() {
+        let server = FakeHolland2Stay::start().await;
+        let sort_fields = server.client().query_sort_fields().await.unwrap();
+        let values: Vec<&str> = sort_fields
+            .iter()
+            .map(|field| field.value.as_str())
+            .collect();
+        assert!(values.contains(&"available_startdate"));
+    }
+
+    #[tokio::test]
+    async fn test_query_houses_in_city() {
+        let server = FakeHolland2Stay::start().await;
+        let listings = server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default())
+            .await
+            .unwrap();
+        assert!(listings.warnings.is_empty());
+        let houses = listings.houses;
+        assert_eq!(houses.len(), 3);
+
+        let house = &houses[0];
+        assert_eq!(house.name, "Kruisplein 12-A");
+        assert_eq!(house.city, rotterdam());
+        assert_eq!(house.rooms.as_deref(), Some("Studio"));
+        assert_eq!(house.finishing.as_deref(), Some("Furnished"));
+        assert_eq!(house.maximum_number_of_persons.as_deref(), Some("One"));
+        assert_eq!(house.floor, Some(2));
+        assert_eq!(house.living_area, Some("24.5".parse().unwrap()));
+        assert_eq!(house.minimum_stay_months, Some(6));
+        assert_eq!(
+            house.price.as_ref().map(ToString::to_string).as_deref(),
+            Some("731.00 EUR")
+        );
+        assert_eq!(
+            house.start_date,
+            chrono::NaiveDate::from_ymd_opt(2025, 3, 5)
+        );
+        assert_eq!(house.contract_type, Some(ContractType::Indefinite));
+        assert_eq!(house.costs.total(), Some("731.00".parse().unwrap()));
+        assert_eq!(house.costs.items().len(), 6);
+
+        assert_eq!(houses[1].floor, Some(0));
+        assert_eq!(houses[1].minimum_stay_months, Some(12));
+        assert!(matches!(
+            houses[1].contract_type,
+            Some(ContractType::Temporary(_))
+        ));
+        assert_eq!(houses[2].start_date, None);
+    }
+
+    #[tokio::test]
+    async fn test_query_houses_in_city_filters_on_city() {
+        let server = FakeHolland2Stay::start().await;
+        server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default())
+            .await
+            .unwrap();
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let body = &house_queries(&requests)[0];
+        assert_eq!(
+            body["variables"]["filters"]["city"],
+            serde_json::json!({ "eq": "25" })
+        );
+        assert_eq!(
+            body["variables"]["filters"]["available_to_book"],
+            serde_json::json!({ "eq": "179" })
+        );
+        assert_eq!(
+            requests[0].headers.get("user-agent").unwrap(),
+            "Mozilla/5.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_houses_in_city_walks_all_pages() {
+        let server = FakeHolland2Stay::start().await;
+        let houses = server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default().page_size(2))
+            .await
+            .unwrap()
+            .houses;
+        assert_eq!(houses.len(), 3);
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let pages: Vec<u64> = house_queries(&requests)
+            .iter()
+            .map(|body| body["variables"]["currentPage"].as_u64().unwrap())
+            .collect();
+        assert_eq!(pages, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_availability_ids_come_from_the_aggregation() {
+        let server = FakeHolland2Stay::start().await;
+        let mut response = fixture("cities.json");
+        for aggregation in response["data"]["products"]["aggregations"]
+            .as_array_mut()
+            .unwrap()
+        {
+            if aggregation["attribute_code"] == "available_to_book" {
+                aggregation["options"][0]["value"] = "500".into();
+                aggregation["options"][1]["value"] = 600.into();
+            }
+        }
+        Mock::given(method("POST"))
+            .and(|request: &wiremock::Request| {
+                request
+                    .body_json::<serde_json::Value>()
+                    .is_ok_and(|body| body["variables"]["filters"].get("city").is_none())
+            })
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let client = server.client();
+        let query = SearchQuery::default().availability(Availability::ALL);
+        client
+            .query_houses_in_city(&rotterdam(), &query)
+            .await
+            .unwrap();
+        client
+            .query_houses_in_city(&rotterdam(), &query)
+            .await
+            .unwrap();
+
+        let requests = server.mock_server().received_requests().await.unwrap();
+        // The ids are only looked up once.
+        assert_eq!(requests.len(), 3);
+        for body in house_queries(&requests) {
+            assert_eq!(
+                body["variables"]["filters"]["available_to_book"],
+                serde_json::json!({ "in": ["500", "600"] })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_availability_options_match_nothing() {
+        let server = FakeHolland2Stay::start().await;
+        let mut response = fixture("cities.json");
+        response["data"]["products"]["aggregations"]
+            .as_array_mut()
+            .unwrap()
+            .retain(|aggregation| aggregation["attribute_code"] != "available_to_book");
+        Mock::given(method("POST"))
+            .and(|request: &wiremock::Request| {
+                request
+                    .body_json::<serde_json::Value>()
+                    .is_ok_and(|body| body["variables"]["filters"].get("city").is_none())
+            })
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let client = server.client();
+        let query = SearchQuery::default().availability(Availability::ALL);
+        for _ in 0..2 {
+            let listings = client
+                .query_houses_in_city(&rotterdam(), &query)
+                .await
+                .unwrap();
+            assert!(listings.houses.is_empty());
+        }
+
+        let requests = server.mock_server().received_requests().await.unwrap();
+        // Nothing can match, so no houses are queried, and the ids are looked up again each time.
+        assert!(house_queries(&requests).is_empty());
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_houses_in_cities() {
+        let server = FakeHolland2Stay::start().await;
+        let results = server
+            .client()
+            .query_houses_in_cities([rotterdam(), delft()].iter(), &SearchQuery::default())
+            .await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[&rotterdam()].as_ref().unwrap().houses.len(), 3);
+        assert!(results[&delft()].as_ref().unwrap().houses.is_empty());
+
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let house_queries = house_queries(&requests);
+        assert_eq!(house_queries.len(), 1);
+        assert_eq!(
+            house_queries[0]["variables"]["filters"]["city"],
+            serde_json::json!({ "in": ["25", "26"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_houses_in_cities_splits_by_city() {
+        let server = FakeHolland2Stay::empty().await;
+        let mut response = fixture("products_rotterdam.json");
+        response["data"]["products"]["items"][1]["city"] = 26.into();
+        response["data"]["products"]["items"][2]["city"] = 99.into();
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .mount(server.mock_server())
+            .await;
+        let results = server
+            .client()
+            .query_houses_in_cities([delft(), rotterdam()].iter(), &SearchQuery::default())
+            .await;
+        let rotterdam_houses = &results[&rotterdam()].as_ref().unwrap().houses;
+        assert_eq!(rotterdam_houses.len(), 1);
+        assert_eq!(rotterdam_houses[0].city, rotterdam());
+        let delft_houses = &results[&delft()].as_ref().unwrap().houses;
+        assert_eq!(delft_houses.len(), 1);
+        assert_eq!(delft_houses[0].sku.as_str(), "RTD-KRP-30B");
+        assert_eq!(delft_houses[0].city.name, "Delft");
+    }
+
+    #[tokio::test]
+    async fn test_query_houses_in_cities_keeps_healthy_cities() {
+        let server = FakeHolland2Stay::start().await;
+        Mock::given(method("POST"))
+            .and(wiremock::matchers::body_partial_json(serde_json::json!({
+                "variables": { "filters": { "city": { "in": ["25", "26"] } } }
+            })))
+            .respond_with(ResponseTemplate::new(500))
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        Mock::given(method("POST"))
+            .and(wiremock::matchers::body_partial_json(serde_json::json!({
+                "variables": { "filters": { "city": { "eq": "26" } } }
+            })))
+            .respond_with(ResponseTemplate::new(500))
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let results = server
+            .client()
+            .query_houses_in_cities([rotterdam(), delft()].iter(), &SearchQuery::default())
+            .await;
+        assert_eq!(results[&rotterdam()].as_ref().unwrap().houses.len(), 3);
+        assert!(matches!(
+            results[&delft()],
+            Err(Holland2StayError::ReqwestError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_error() {
+        let server = FakeHolland2Stay::empty().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(server.mock_server())
+            .await;
+        let error = server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Holland2StayError::ReqwestError(_)));
+        let requests = server.mock_server().received_requests().await.unwrap();
+        assert_eq!(
+            requests.len(),
+            1 + RetryPolicy::default().max_retries as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let server = FakeHolland2Stay::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let houses = server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default())
+            .await
+            .unwrap()
+            .houses;
+        assert_eq!(houses.len(), 3);
+        // The lookup of the availability ids is retried twice, the query for houses goes through.
+        let requests = server.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors() {
+        let server = FakeHolland2Stay::empty().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(server.mock_server())
+            .await;
+        server.client().query_cities().await.unwrap_err();
+        let requests = server.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_honours_retry_after() {
+        let server = FakeHolland2Stay::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "1"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let start = std::time::Instant::now();
+        server.client().query_cities().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_on_long_retry_after() {
+        let server = FakeHolland2Stay::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503).insert_header("retry-after", "3600"))
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let error = server.client().query_cities().await.unwrap_err();
+        assert!(matches!(error, Holland2StayError::ReqwestError(_)));
+        let requests = server.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let server = FakeHolland2Stay::start().await;
+        let client = Holland2StayClient::builder()
+            .api_url(server.api_url())
+            .rate_limit(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let clients = [client.clone(), client.clone(), client];
+        let start = std::time::Instant::now();
+        futures::future::join_all(clients.iter().map(Holland2StayClient::query_cities)).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_response() {
+        let server = FakeHolland2Stay::empty().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "data": null })),
+            )
+            .mount(server.mock_server())
+            .await;
+        let error = server.client().query_cities().await.unwrap_err();
+        assert!(matches!(error, Holland2StayError::ConversionError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_query_residence_details() {
+        let server = FakeHolland2Stay::start().await;
+        let details = server
+            .client()
+            .query_residence_details(&Sku::from("RTD-KRP-12A".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(details.url_key, "kruisplein-12-a");
+        assert!(
+            details
+                .description
+                .as_deref()
+                .unwrap()
+                .starts_with("A furnished studio in the centre of Rotterdam")
+        );
+        assert_eq!(
+            details.amenities,
+            [
+                "Private bathroom & toilet",
+                "Kitchenette with induction hob",
+                "Washing machine"
+            ]
+        );
+        let labels: Vec<Option<&str>> = details
+            .images
+            .iter()
+            .map(|image| image.label.as_deref())
+            .collect();
+        assert_eq!(
+            labels,
+            [Some("Living room"), Some("Kitchen"), Some("Floor plan")]
+        );
+        assert_eq!(
+            details.main_photo().unwrap().url.as_str(),
+            "https://holland2stay.com/media/catalog/product/k/r/kruisplein-12a-living-room.jpg"
+        );
+        assert_eq!(
+            details.floor_plan.unwrap().label.as_deref(),
+            Some("Floor plan")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_residence_details_not_found() {
+        let server = FakeHolland2Stay::start().await;
+        let error = server
+            .client()
+            .query_residence_details(&Sku::from("RTD-XXX-1".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Holland2StayError::ListingNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_graphql_errors() {
+        let server = FakeHolland2Stay::empty().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "errors": [{
+                    "message": "Field \"city\" is not defined by type \"ProductAttributeFilterInput\".",
+                    "locations": [{ "line": 1, "column": 60 }],
+                    "extensions": { "category": "graphql" },
+                }],
+            })))
+            .mount(server.mock_server())
+            .await;
+        let error = server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default())
+            .await
+            .unwrap_err();
+        let Holland2StayError::GraphQl(errors) = error else {
+            panic!("unexpected error {:?}", error);
+        };
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("ProductAttributeFilterInput"));
+        assert_eq!(
+            errors[0].extensions,
+            Some(serde_json::json!({ "category": "graphql" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_city_aggregation() {
+        let server = FakeHolland2Stay::empty().await;
+        let mut response = fixture("cities.json");
+        response["data"]["products"]["aggregations"] = serde_json::json!([]);
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .mount(server.mock_server())
+            .await;
+        let error = server.client().query_cities().await.unwrap_err();
+        assert!(matches!(error, Holland2StayError::MissingCityAggregation));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_listing_fields() {
+        let server = FakeHolland2Stay::empty().await;
+        let mut response = fixture("products_rotterdam.json");
+        let items = &mut response["data"]["products"]["items"];
+        items[0]["next_contract_startdate"] = "5 March 2025".into();
+        items[0]["basic_rent"] = "n/a".into();
+        items[1]["floor"] = 1234.into();
+        items[2]["sku"] = serde_json::Value::Null;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .mount(server.mock_server())
+            .await;
+
+        let listings = server
+            .client()
+            .query_houses_in_city(&rotterdam(), &SearchQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(listings.houses.len(), 2);
+        assert_eq!(listings.houses[0].start_date, None);
+        assert_eq!(listings.houses[0].costs.basic_rent, None);
+        assert!(listings.houses[0].costs.service_charge.is_some());
+        assert_eq!(listings.houses[1].floor, None);
+
+        let invalid_fields: Vec<(&str, &str)> = listings
+            .warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                ListingWarning::InvalidField { sku, field, .. } => Some((sku.as_str(), *field)),
+                ListingWarning::InvalidListing { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            invalid_fields,
+            [
+                ("RTD-KRP-12A", "basic_rent"),
+                ("RTD-KRP-12A", "next_contract_startdate"),
+                ("RTD-KRP-30B", "floor"),
+            ]
+        );
+        assert!(listings.warnings.iter().any(|warning| matches!(
+            warning,
+            ListingWarning::InvalidListing { sku, .. } if sku == "unknown"
+        )));
+    }
+}
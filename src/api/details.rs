@@ -0,0 +1,204 @@
+use reqwest::Url;
+use serde_json::json;
+
+use super::{Holland2StayError, Sku, api_response::Products};
+
+const PRODUCT_DETAILS_QUERY: &str = "query GetProductDetails($sku: String!) { products(filter: { sku: { eq: $sku } }) { items { sku, url_key, name, description { html, __typename }, media_gallery { url, label, position, disabled, __typename }, __typename }, total_count, __typename } }";
+
+/// An image of the media gallery of a residence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaImage {
+    pub url: Url,
+    pub label: Option<String>,
+}
+
+impl MediaImage {
+    fn is_floor_plan(&self) -> bool {
+        self.label.as_deref().is_some_and(|label| {
+            let label = label.to_lowercase();
+            label.contains("floor plan")
+                || label.contains("floorplan")
+                || label.contains("plattegrond")
+        })
+    }
+}
+
+/// The parts of a residence's product page that the products search does not return.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResidenceDetails {
+    pub sku: Sku,
+    pub url_key: String,
+    /// The description as plain text, one paragraph per line.
+    pub description: Option<String>,
+    /// The enabled images, in the order the website shows them, floor plan included.
+    pub images: Vec<MediaImage>,
+    pub floor_plan: Option<MediaImage>,
+    /// The items of the lists in the description, which is where holland2stay lists what a
+    /// residence comes with.
+    pub amenities: Vec<String>,
+}
+
+impl ResidenceDetails {
+    /// The first image that is not the floor plan.
+    pub fn main_photo(&self) -> Option<&MediaImage> {
+        self.images.iter().find(|image| !image.is_floor_plan())
+    }
+}
+
+pub(super) mod api_details {
+    #[derive(serde::Deserialize)]
+    pub struct ApiProductDetails {
+        pub sku: String,
+        pub url_key: String,
+        pub description: Option<Description>,
+        #[serde(default)]
+        pub media_gallery: Option<Vec<ApiMediaImage>>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct Description {
+        pub html: Option<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct ApiMediaImage {
+        pub url: Option<String>,
+        pub label: Option<String>,
+        pub position: Option<i64>,
+        pub disabled: Option<bool>,
+    }
+}
+
+pub(super) fn to_graphql_body(sku: &Sku) -> serde_json::Value {
+    json!({
+        "operationName": "GetProductDetails",
+        "variables": { "sku": sku.as_str() },
+        "query": PRODUCT_DETAILS_QUERY,
+    })
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Turns the description html into plain text, with a line per paragraph, line break or list
+/// item, and collects the text of the list items.
+fn html_to_text(html: &str) -> (String, Vec<String>) {
+    let mut lines = vec![String::new()];
+    let mut list_items = Vec::new();
+    let mut in_list_item = false;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        lines.last_mut().unwrap().push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim().to_lowercase();
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .collect();
+        if ["p", "br", "li", "ul", "ol", "div", "h1", "h2", "h3", "h4"].contains(&name.as_str()) {
+            if in_list_item {
+                list_items.push(lines.last().unwrap().clone());
+            }
+            in_list_item = name == "li" && !tag.starts_with('/');
+            lines.push(String::new());
+        }
+        rest = &rest[start + end + 1..];
+    }
+    lines.last_mut().unwrap().push_str(rest);
+    let clean = |line: &str| {
+        decode_entities(
+            line.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .as_str(),
+        )
+    };
+    let text = lines
+        .iter()
+        .map(|line| clean(line))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let list_items = list_items
+        .iter()
+        .map(|item| clean(item))
+        .filter(|item| !item.is_empty())
+        .collect();
+    (text, list_items)
+}
+
+pub(super) fn parse_details(
+    products: Products<api_details::ApiProductDetails>,
+    sku: &Sku,
+) -> Result<ResidenceDetails, Holland2StayError> {
+    let details = products
+        .items
+        .ok_or_else(super::conversion_error)?
+        .into_iter()
+        .find(|item| item.sku == sku.as_str())
+        .ok_or_else(|| Holland2StayError::ListingNotFound(sku.clone()))?;
+
+    let (description, amenities) = details
+        .description
+        .and_then(|description| description.html)
+        .map(|html| html_to_text(&html))
+        .map_or((None, vec![]), |(text, amenities)| {
+            ((!text.is_empty()).then_some(text), amenities)
+        });
+
+    let mut gallery: Vec<_> = details
+        .media_gallery
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|image| image.disabled != Some(true))
+        .filter_map(|image| {
+            let url = Url::parse(image.url.as_deref()?).ok()?;
+            Some((
+                image.position.unwrap_or(i64::MAX),
+                MediaImage {
+                    url,
+                    label: image.label,
+                },
+            ))
+        })
+        .collect();
+    gallery.sort_by_key(|(position, _)| *position);
+    let images: Vec<MediaImage> = gallery.into_iter().map(|(_, image)| image).collect();
+    let floor_plan = images.iter().find(|image| image.is_floor_plan()).cloned();
+
+    Ok(ResidenceDetails {
+        sku: Sku(details.sku),
+        url_key: details.url_key,
+        description,
+        images,
+        floor_plan,
+        amenities,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_to_text() {
+        let (text, list_items) = html_to_text(
+            "<p>Studio near <b>Centraal</b>.</p><p>Includes:</p><ul>\n<li>Bath &amp; toilet</li><li> Washing   machine </li></ul><p>Pets&nbsp;not allowed",
+        );
+        assert_eq!(
+            text,
+            "Studio near Centraal.\nIncludes:\nBath & toilet\nWashing machine\nPets not allowed"
+        );
+        assert_eq!(list_items, ["Bath & toilet", "Washing machine"]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{StatusCode, header::HeaderMap};
+use tokio::{sync::Mutex, time::Instant};
+
+/// How failed requests are retried: timeouts, connection errors, 429s and 5xx responses are
+/// retried with exponential backoff, other failures are returned right away.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    /// Upper bound on the backoff. A `Retry-After` longer than this is not waited for.
+    pub max_backoff: Duration,
+    /// The fraction of the backoff that is randomized, between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retries() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// The backoff before retry number `retry`, starting at 0.
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f64(1.0 - jitter * rand::rng().random::<f64>())
+    }
+
+    pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+
+    pub(crate) fn is_retryable_error(error: &reqwest::Error) -> bool {
+        error.is_timeout() || error.is_connect()
+    }
+}
+
+/// Parses a `Retry-After` header, given either in seconds or as an http date.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Spaces requests at least `min_interval` apart, no matter how many are sent concurrently.
+#[derive(Debug)]
+pub struct RateLimiter {
+    min_interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        RateLimiter {
+            min_interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.min_interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.0,
+        };
+        let backoffs: Vec<u64> = (0..5)
+            .map(|retry| policy.backoff(retry).as_secs())
+            .collect();
+        assert_eq!(backoffs, [1, 2, 4, 5, 5]);
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let backoff = policy.backoff(2);
+            assert!(backoff >= Duration::from_secs(2) && backoff <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(reqwest::header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let rate_limiter = RateLimiter::new(Duration::from_millis(100));
+        let start = Instant::now();
+        futures::future::join_all((0..3).map(|_| rate_limiter.acquire())).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use serde_json::json;
+
+use super::{CityId, DEFAULT_PAGE_SIZE, api_response};
+
+const PRODUCTS_QUERY: &str = "query GetCategories($pageSize: Int!, $currentPage: Int!, $filters: ProductAttributeFilterInput!, $sort: ProductAttributeSortInput) { products( pageSize: $pageSize, currentPage: $currentPage, filter: $filters, sort: $sort ) { ...ProductsFragment, __typename } } fragment ProductsFragment on Products { sort_fields { options { label, value, __typename }, __typename }, aggregations { label, count, attribute_code, options { label, count, value, __typename }, position, __typename }, items { name, sku, city, url_key, available_to_book, available_startdate, next_contract_startdate, current_lottery_subscribers, building_name, finishing, living_area, no_of_rooms, resident_type, offer_text_two, offer_text, maximum_number_of_persons, type_of_contract, price_analysis_text, allowance_price, floor, basic_rent, lumpsum_service_charge, inventory, caretaker_costs, cleaning_common_areas, energy_common_areas, energy_label, minimum_stay, allowance_price, price_range { minimum_price { regular_price { value, currency, __typename }, final_price { value, currency, __typename }, __typename }, maximum_price { regular_price { value, currency, __typename }, final_price { value, currency, __typename }, __typename }, __typename } , __typename }, total_count, __typename }";
+
+/// The category uid of residences.
+pub const RESIDENCES_CATEGORY: &str = "Nw==";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, derive_more::Display)]
+pub enum Availability {
+    #[display("direct booking")]
+    DirectBooking,
+    #[display("lottery")]
+    Lottery,
+}
+
+impl Availability {
+    pub const ALL: [Availability; 2] = [Availability::DirectBooking, Availability::Lottery];
+
+    /// Parses the label of an `available_to_book` option, e.g. "Available in lottery".
+    pub fn from_label(label: &str) -> Option<Self> {
+        let label = label.to_lowercase();
+        if label.contains("lottery") {
+            Some(Availability::Lottery)
+        } else if label.contains("available to book") || label.contains("book directly") {
+            Some(Availability::DirectBooking)
+        } else {
+            None
+        }
+    }
+}
+
+/// The `available_to_book` option id of every [`Availability`], built from the
+/// `available_to_book` aggregation like [`CityRegistry`](super::CityRegistry).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AvailabilityRegistry {
+    ids: HashMap<Availability, String>,
+}
+
+impl AvailabilityRegistry {
+    /// Options whose label is not recognized by [`Availability::from_label`] are skipped.
+    pub(super) fn from_aggregations(aggregations: &[api_response::Aggregation]) -> Self {
+        let mut ids = HashMap::new();
+        let options = aggregations
+            .iter()
+            .filter(|aggregation| aggregation.attribute_code == "available_to_book")
+            .flat_map(|aggregation| &aggregation.options);
+        for option in options {
+            if let Some(availability) = Availability::from_label(&option.label) {
+                ids.entry(availability)
+                    .or_insert_with(|| option.value.clone());
+            }
+        }
+        AvailabilityRegistry { ids }
+    }
+
+    /// The `available_to_book` option id of `availability`.
+    pub fn id(&self, availability: Availability) -> Option<&str> {
+        self.ids.get(&availability).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Whether there is an option for every [`Availability`]. Holland2stay leaves out the options
+    /// no residence has right now, such as the lottery when there are no lottery listings.
+    pub fn is_complete(&self) -> bool {
+        Availability::ALL
+            .iter()
+            .all(|availability| self.ids.contains_key(availability))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, derive_more::Display)]
+pub enum SortDirection {
+    #[display("ASC")]
+    Ascending,
+    #[display("DESC")]
+    Descending,
+}
+
+/// A field products can be sorted by, as listed in the `sort_fields` of a products response.
+#[derive(Clone, PartialEq, Eq, Debug, serde::Deserialize)]
+pub struct SortField {
+    pub label: String,
+    /// The value to pass to [`SearchQuery::sort_by`].
+    pub value: String,
+}
+
+/// An inclusive range, either end of which may be left open.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Range {
+    pub from: Option<Decimal>,
+    pub to: Option<Decimal>,
+}
+
+impl Range {
+    fn to_filter(self) -> Option<serde_json::Value> {
+        if self.from.is_none() && self.to.is_none() {
+            return None;
+        }
+        let mut filter = serde_json::Map::new();
+        if let Some(from) = self.from {
+            filter.insert("from".to_string(), json!(from.to_string()));
+        }
+        if let Some(to) = self.to {
+            filter.insert("to".to_string(), json!(to.to_string()));
+        }
+        Some(filter.into())
+    }
+}
+
+/// The filters, sorting and page size of a products query.
+///
+/// The default query matches what the holland2stay website shows: residences that can be booked
+/// directly, sorted by the date they become available.
+#[derive(Clone, Debug)]
+pub struct SearchQuery {
+    availability: Vec<Availability>,
+    categories: Vec<String>,
+    cities: Vec<CityId>,
+    price: Range,
+    living_area: Range,
+    rooms: Vec<String>,
+    sort_field: String,
+    sort_direction: SortDirection,
+    pub(super) page_size: u32,
+}
+
+impl Default for SearchQuery {
+    fn default() -> Self {
+        SearchQuery {
+            availability: vec![Availability::DirectBooking],
+            categories: vec![RESIDENCES_CATEGORY.to_string()],
+            cities: vec![],
+            price: Range::default(),
+            living_area: Range::default(),
+            rooms: vec![],
+            sort_field: "available_startdate".to_string(),
+            sort_direction: SortDirection::Ascending,
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+impl SearchQuery {
+    /// Restricts the query to houses with one of the given availabilities, or to any
+    /// availability if `availability` is empty.
+    pub fn availability(mut self, availability: impl IntoIterator<Item = Availability>) -> Self {
+        self.availability = availability.into_iter().collect();
+        self
+    }
+
+    pub fn categories(mut self, categories: impl IntoIterator<Item = String>) -> Self {
+        self.categories = categories.into_iter().collect();
+        self
+    }
+
+    pub fn cities(mut self, cities: impl IntoIterator<Item = CityId>) -> Self {
+        self.cities = cities.into_iter().collect();
+        self
+    }
+
+    pub fn price(mut self, from: Option<Decimal>, to: Option<Decimal>) -> Self {
+        self.price = Range { from, to };
+        self
+    }
+
+    pub fn living_area(mut self, from: Option<Decimal>, to: Option<Decimal>) -> Self {
+        self.living_area = Range { from, to };
+        self
+    }
+
+    /// Restricts the query to the given `no_of_rooms` option ids.
+    pub fn rooms(mut self, rooms: impl IntoIterator<Item = String>) -> Self {
+        self.rooms = rooms.into_iter().collect();
+        self
+    }
+
+    /// Sorts by `field`, which should be the value of one of the [`SortField`]s holland2stay returns.
+    pub fn sort_by(mut self, field: impl Into<String>, direction: SortDirection) -> Self {
+        self.sort_field = field.into();
+        self.sort_direction = direction;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Whether the query filters on availability, and so needs the [`AvailabilityRegistry`].
+    pub(super) fn filters_availability(&self) -> bool {
+        !self.availability.is_empty()
+    }
+
+    /// The option ids of the availabilities the query filters on. Availabilities without an option
+    /// are left out, as no residence has them.
+    fn availability_option_ids(&self, availability_ids: &AvailabilityRegistry) -> Vec<String> {
+        self.availability
+            .iter()
+            .filter_map(|&availability| availability_ids.id(availability))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Whether no residence can match the query, because it filters on availability and none of
+    /// its availabilities has an option in `availability_ids`.
+    pub(super) fn matches_nothing(&self, availability_ids: &AvailabilityRegistry) -> bool {
+        self.filters_availability() && self.availability_option_ids(availability_ids).is_empty()
+    }
+
+    fn filters(&self, availability_ids: &AvailabilityRegistry) -> serde_json::Value {
+        fn equal_filter(values: Vec<String>) -> Option<serde_json::Value> {
+            match values.as_slice() {
+                [] => None,
+                [value] => Some(json!({ "eq": value })),
+                values => Some(json!({ "in": values })),
+            }
+        }
+
+        let availability_ids = self.availability_option_ids(availability_ids);
+        let mut filters = serde_json::Map::new();
+        let mut insert = |attribute_code: &str, filter: Option<serde_json::Value>| {
+            if let Some(filter) = filter {
+                filters.insert(attribute_code.to_string(), filter);
+            }
+        };
+        insert("available_to_book", equal_filter(availability_ids));
+        insert("category_uid", equal_filter(self.categories.clone()));
+        insert(
+            "city",
+            equal_filter(self.cities.iter().map(CityId::to_string).collect()),
+        );
+        insert("price", self.price.to_filter());
+        insert("living_area", self.living_area.to_filter());
+        insert("no_of_rooms", equal_filter(self.rooms.clone()));
+        filters.into()
+    }
+
+    /// The json body of the graphql request for page `current_page`, starting at 1. Check
+    /// [`SearchQuery::matches_nothing`] first: without any availability option the query would
+    /// not filter on availability at all.
+    pub(super) fn to_graphql_body(
+        &self,
+        current_page: u32,
+        availability_ids: &AvailabilityRegistry,
+    ) -> serde_json::Value {
+        json!({
+            "operationName": "GetCategories",
+            "variables": {
+                "currentPage": current_page,
+                "filters": self.filters(availability_ids),
+                "pageSize": self.page_size,
+                "sort": { self.sort_field.clone(): self.sort_direction.to_string() },
+            },
+            "query": PRODUCTS_QUERY,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn availability_ids() -> AvailabilityRegistry {
+        let aggregations: Vec<api_response::Aggregation> = serde_json::from_value(json!([
+            { "attribute_code": "city", "options": [ { "label": "Rotterdam", "value": "25" } ] },
+            { "attribute_code": "available_to_book", "options": [
+                { "label": "Available to book", "value": "179" },
+                { "label": "Available in lottery", "value": "336" },
+                { "label": "Not available", "value": "180" }
+            ] }
+        ]))
+        .unwrap();
+        AvailabilityRegistry::from_aggregations(&aggregations)
+    }
+
+    #[test]
+    fn test_default_filters() {
+        let body = SearchQuery::default().to_graphql_body(1, &availability_ids());
+        assert_eq!(
+            body["variables"]["filters"],
+            json!({
+                "available_to_book": { "eq": "179" },
+                "category_uid": { "eq": "Nw==" },
+            })
+        );
+        assert_eq!(
+            body["variables"]["sort"],
+            json!({ "available_startdate": "ASC" })
+        );
+        assert_eq!(body["variables"]["pageSize"], json!(DEFAULT_PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_custom_filters() {
+        let body = SearchQuery::default()
+            .availability([Availability::DirectBooking, Availability::Lottery])
+            .cities([CityId(25), CityId(29)])
+            .price(None, Some(Decimal::new(900, 0)))
+            .living_area(Some(Decimal::new(255, 1)), None)
+            .rooms(["104".to_string()])
+            .sort_by("price", SortDirection::Descending)
+            .page_size(20)
+            .to_graphql_body(3, &availability_ids());
+        assert_eq!(
+            body["variables"],
+            json!({
+                "currentPage": 3,
+                "filters": {
+                    "available_to_book": { "in": ["179", "336"] },
+                    "category_uid": { "eq": "Nw==" },
+                    "city": { "in": ["25", "29"] },
+                    "price": { "to": "900" },
+                    "living_area": { "from": "25.5" },
+                    "no_of_rooms": { "eq": "104" },
+                },
+                "pageSize": 20,
+                "sort": { "price": "DESC" },
+            })
+        );
+    }
+
+    #[test]
+    fn test_availability_registry() {
+        let ids = availability_ids();
+        assert_eq!(ids.id(Availability::DirectBooking), Some("179"));
+        assert_eq!(ids.id(Availability::Lottery), Some("336"));
+
+        assert!(ids.is_complete());
+
+        // Without lottery listings there is no lottery option, the query only asks for the rest.
+        let aggregations: Vec<api_response::Aggregation> = serde_json::from_value(json!([
+            { "attribute_code": "available_to_book", "options": [
+                { "label": "Available to book", "value": "179" }
+            ] }
+        ]))
+        .unwrap();
+        let direct_only = AvailabilityRegistry::from_aggregations(&aggregations);
+        assert!(!direct_only.is_complete());
+        let query = SearchQuery::default().availability(Availability::ALL);
+        assert!(!query.matches_nothing(&direct_only));
+        assert_eq!(
+            query.to_graphql_body(1, &direct_only)["variables"]["filters"]["available_to_book"],
+            json!({ "eq": "179" })
+        );
+
+        let no_ids = AvailabilityRegistry::default();
+        assert!(!no_ids.is_complete());
+        assert!(SearchQuery::default().matches_nothing(&no_ids));
+        let query = SearchQuery::default().availability([]);
+        assert!(!query.matches_nothing(&no_ids));
+        let body = query.to_graphql_body(1, &no_ids);
+        assert!(
+            body["variables"]["filters"]
+                .get("available_to_book")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_availability_from_label() {
+        assert_eq!(
+            Availability::from_label("Available to book"),
+            Some(Availability::DirectBooking)
+        );
+        assert_eq!(
+            Availability::from_label("Available in lottery"),
+            Some(Availability::Lottery)
+        );
+        assert_eq!(Availability::from_label("Not available"), None);
+    }
+
+    #[test]
+    fn test_values_are_escaped() {
+        let body = SearchQuery::default()
+            .categories([r#"Nw==" }, "city": { "eq": "1"#.to_string()])
+            .to_graphql_body(1, &availability_ids());
+        let body: serde_json::Value = serde_json::from_str(&body.to_string()).unwrap();
+        assert_eq!(
+            body["variables"]["filters"]["category_uid"]["eq"],
+            json!(r#"Nw==" }, "city": { "eq": "1"#)
+        );
+        assert!(body["variables"]["filters"].get("city").is_none());
+    }
+}
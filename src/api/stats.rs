@@ -0,0 +1,257 @@
+use rust_decimal::Decimal;
+
+use super::{Availability, City, ContractType, House, Listings, parse_floor};
+
+/// An option of an attribute and the number of listings that have it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionCount {
+    /// The option id, e.g. the id of a city.
+    pub value: String,
+    pub label: String,
+    pub count: u64,
+}
+
+/// The number of listings per option of an attribute, as returned in the `aggregations` of a
+/// products response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeCounts {
+    pub attribute_code: String,
+    pub label: String,
+    pub options: Vec<OptionCount>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, derive_more::Display)]
+#[display("min {min:.2}, median {median:.2}, max {max:.2}")]
+pub struct PriceDistribution {
+    pub min: Decimal,
+    /// The mean of the middle two prices if there is an even number of them.
+    pub median: Decimal,
+    pub max: Decimal,
+}
+
+impl PriceDistribution {
+    /// The distribution of `prices`, or `None` if there are none.
+    pub fn from_prices(prices: impl IntoIterator<Item = Decimal>) -> Option<Self> {
+        let mut prices: Vec<Decimal> = prices.into_iter().collect();
+        prices.sort();
+        let (&min, &max) = (prices.first()?, prices.last()?);
+        let middle = prices.len() / 2;
+        let median = if prices.len().is_multiple_of(2) {
+            (prices[middle - 1] + prices[middle]) / Decimal::TWO
+        } else {
+            prices[middle]
+        };
+        Some(PriceDistribution { min, median, max })
+    }
+}
+
+/// A snapshot of the market in a city, from the aggregations of a products response and the
+/// prices of the houses it listed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MarketStats {
+    pub city: City,
+    /// The number of listings holland2stay reported, including those that could not be parsed.
+    pub listing_count: u64,
+    /// The counts per option of the other attributes, such as finishing or number of rooms. If the
+    /// response also covered other cities, the houses of this city are counted instead, which
+    /// leaves out listings that could not be parsed and attributes houses do not keep.
+    pub attributes: Vec<AttributeCounts>,
+    /// The distribution of the prices of the houses that have one.
+    pub price: Option<PriceDistribution>,
+}
+
+impl MarketStats {
+    /// The stats of `city` from `listings`, which may also contain the houses of other cities.
+    pub fn new(city: &City, listings: &Listings) -> Self {
+        let city_id = city.id.to_string();
+        let city_options = listings
+            .aggregations
+            .iter()
+            .find(|attribute| attribute.attribute_code == "city")
+            .map(|attribute| attribute.options.as_slice());
+        let houses = listings.houses.iter().filter(|house| house.city == *city);
+        let listing_count = city_options
+            .and_then(|options| options.iter().find(|option| option.value == city_id))
+            .map_or_else(|| houses.clone().count() as u64, |option| option.count);
+        let only_this_city = city_options
+            .is_some_and(|options| options.iter().all(|option| option.value == city_id));
+        let other_attributes = listings
+            .aggregations
+            .iter()
+            .filter(|attribute| attribute.attribute_code != "city");
+        let attributes = if only_this_city {
+            other_attributes.cloned().collect()
+        } else {
+            let houses: Vec<&House> = houses.clone().collect();
+            other_attributes
+                .filter_map(|attribute| count_options(attribute, &houses))
+                .collect()
+        };
+        MarketStats {
+            city: city.clone(),
+            listing_count,
+            attributes,
+            price: PriceDistribution::from_prices(
+                houses.filter_map(|house| house.price.as_ref().map(|price| price.amount)),
+            ),
+        }
+    }
+
+    pub fn attribute(&self, attribute_code: &str) -> Option<&AttributeCounts> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.attribute_code == attribute_code)
+    }
+}
+
+/// Counts the options of `attribute` among `houses`, leaving out the options none of them have.
+/// `None` if houses do not keep the attribute, or none of them has any of its options.
+fn count_options(attribute: &AttributeCounts, houses: &[&House]) -> Option<AttributeCounts> {
+    let mut options = Vec::new();
+    for option in &attribute.options {
+        let mut count = 0;
+        for house in houses {
+            count += u64::from(has_option(house, &attribute.attribute_code, &option.label)?);
+        }
+        if count > 0 {
+            options.push(OptionCount {
+                count,
+                ..option.clone()
+            });
+        }
+    }
+    (!options.is_empty()).then(|| AttributeCounts {
+        options,
+        ..attribute.clone()
+    })
+}
+
+/// Whether `house` has the option of the attribute `attribute_code` labelled `label`, or `None`
+/// if houses do not keep the attribute.
+fn has_option(house: &House, attribute_code: &str, label: &str) -> Option<bool> {
+    let is_label = |value: &Option<String>| value.as_deref() == Some(label);
+    Some(match attribute_code {
+        "finishing" => is_label(&house.finishing),
+        "no_of_rooms" => is_label(&house.rooms),
+        "resident_type" => is_label(&house.resident_type),
+        "maximum_number_of_persons" => is_label(&house.maximum_number_of_persons),
+        "energy_label" => is_label(&house.energy_label),
+        "floor" => house.floor.is_some() && house.floor == parse_floor(label),
+        "type_of_contract" => house
+            .contract_type
+            .as_ref()
+            .is_some_and(|contract_type| *contract_type == ContractType::from_label(label)),
+        "available_to_book" => {
+            house.availability.is_some() && house.availability == Availability::from_label(label)
+        }
+        _ => return None,
+    })
+}
+
+impl std::fmt::Display for MarketStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} listings", self.city, self.listing_count)?;
+        match &self.price {
+            Some(price) => write!(f, "\nPrice: {}", price)?,
+            None => write!(f, "\nPrice: unknown")?,
+        }
+        for attribute in &self.attributes {
+            write!(
+                f,
+                "\n{}: {}",
+                attribute.label,
+                itertools::join(
+                    attribute
+                        .options
+                        .iter()
+                        .map(|option| format!("{} ({})", option.label, option.count)),
+                    ", "
+                )
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::parse_products_response;
+    use crate::test_server::{delft, rotterdam};
+
+    fn listings(cities: &[City]) -> Listings {
+        parse_products_response(
+            include_bytes!("../../fixtures/products_rotterdam.json"),
+            cities,
+            &reqwest::Url::parse(super::super::client::DEFAULT_RESIDENCES_URL).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_price_distribution() {
+        let prices = |prices: &[i64]| {
+            PriceDistribution::from_prices(prices.iter().map(|&price| Decimal::new(price, 0)))
+        };
+        assert_eq!(prices(&[]), None);
+        assert_eq!(
+            prices(&[700, 500, 900]),
+            Some(PriceDistribution {
+                min: Decimal::new(500, 0),
+                median: Decimal::new(700, 0),
+                max: Decimal::new(900, 0),
+            })
+        );
+        assert_eq!(
+            prices(&[800, 500, 900, 700]).map(|prices| prices.median),
+            Some(Decimal::new(750, 0))
+        );
+    }
+
+    #[test]
+    fn test_market_stats() {
+        let stats = MarketStats::new(&rotterdam(), &listings(&[rotterdam()]));
+        assert_eq!(stats.listing_count, 3);
+        assert_eq!(
+            stats.price.map(|price| price.to_string()).as_deref(),
+            Some("min 684.28, median 731.00, max 1129.50")
+        );
+        let finishing = stats.attribute("finishing").unwrap();
+        assert_eq!(finishing.label, "Finishing");
+        assert_eq!(finishing.options[0].label, "Furnished");
+        assert_eq!(finishing.options[0].count, 2);
+        assert!(stats.attribute("city").is_none());
+        assert!(
+            stats.to_string().starts_with(
+                "Rotterdam: 3 listings\nPrice: min 684.28, median 731.00, max 1129.50\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_market_stats_of_several_cities() {
+        let mut listings = listings(&[rotterdam(), delft()]);
+        let city_options = &mut listings.aggregations[0].options;
+        city_options.push(OptionCount {
+            value: "26".to_string(),
+            label: "Delft".to_string(),
+            count: 4,
+        });
+        let stats = MarketStats::new(&delft(), &listings);
+        assert_eq!(stats.listing_count, 4);
+        assert!(stats.attributes.is_empty());
+        assert_eq!(stats.price, None);
+
+        // The attributes of rotterdam are counted from its houses instead.
+        let stats = MarketStats::new(&rotterdam(), &listings);
+        let single_city = MarketStats::new(&rotterdam(), &self::listings(&[rotterdam()]));
+        for attribute_code in ["finishing", "no_of_rooms", "floor", "type_of_contract"] {
+            assert_eq!(
+                stats.attribute(attribute_code),
+                single_city.attribute(attribute_code),
+                "{attribute_code}"
+            );
+        }
+        assert!(stats.attribute("price").is_none());
+    }
+}
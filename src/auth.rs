@@ -1,50 +1,425 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use reqwest::{Client, Url, cookie, cookie::CookieStore};
+use chrono::{DateTime, TimeDelta, Utc};
+use reqwest::{Client, Response, StatusCode, Url, header::LOCATION, redirect};
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
 
-use crate::api::Holland2StayError;
+use crate::accounts::{CipherError, CredentialsKey, decrypt, encrypt};
+use crate::api::{Holland2StayError, RetryPolicy, retry_after};
 
-#[derive(derive_new::new)]
+/// How long before it expires a session is renewed.
+pub const SESSION_REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(5);
+
+#[derive(Clone, derive_new::new, serde::Serialize, serde::Deserialize)]
 pub struct Auth {
     username: String,
     password: String,
 }
 
+impl Auth {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+/// Where and as whom to log in again once a session expires.
+#[derive(Clone)]
+struct Credentials {
+    base_url: Url,
+    auth: Auth,
+}
+
 #[derive(derive_new::new)]
 pub struct Login {
     client: Client,
     bearer_token: String,
+    /// When the session expires, if the session endpoint said so.
+    #[new(default)]
+    expires: Option<DateTime<Utc>>,
+    #[new(default)]
+    credentials: Option<Credentials>,
+    /// The cookies `client` sends, if this login created the client.
+    #[new(default)]
+    cookies: Arc<CookieStoreMutex>,
+    /// Where the session is saved whenever it is renewed.
+    #[new(default)]
+    store: Option<SessionStore>,
 }
 
-fn holland2stay_base_url() -> Url {
+impl Login {
+    /// The client holding the session cookies of this login.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn bearer_token(&self) -> &str {
+        &self.bearer_token
+    }
+
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.expires
+    }
+
+    /// Whether the session has expired at `now`, or will within [`SESSION_REFRESH_MARGIN`].
+    pub fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires
+            .is_some_and(|expires| now + SESSION_REFRESH_MARGIN >= expires)
+    }
+
+    /// Runs the whole login flow again with the credentials of this login, retrying transient
+    /// failures with the backoff of `retry_policy`.
+    pub async fn relogin(&mut self, retry_policy: &RetryPolicy) -> Result<(), Holland2StayError> {
+        let credentials = self
+            .credentials
+            .clone()
+            .ok_or(Holland2StayError::SessionExpired)?;
+        let mut retry = 0;
+        loop {
+            match login_holland2stay_at(&credentials.base_url, &credentials.auth).await {
+                Ok(login) => {
+                    let store = self.store.take();
+                    *self = login;
+                    self.store = store;
+                    self.save_session();
+                    return Ok(());
+                }
+                Err(err) if err.is_transient() && retry < retry_policy.max_retries => {
+                    let backoff = match &err {
+                        AuthError::RateLimited {
+                            retry_after: Some(retry_after),
+                        } => *retry_after,
+                        _ => retry_policy.backoff(retry),
+                    };
+                    if backoff > retry_policy.max_backoff {
+                        return Err(err.into());
+                    }
+                    log::warn!(
+                        "Could not log in to holland2stay again: {}, retrying in {:?}",
+                        err,
+                        backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    retry += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Saves the session to `store` now and whenever it is renewed.
+    pub fn persist_to(&mut self, store: SessionStore) -> Result<(), SessionStoreError> {
+        store.save(self)?;
+        self.store = Some(store);
+        Ok(())
+    }
+
+    /// Saves the session to the store of this login, if it has one. Failing to do so only means
+    /// the next run has to log in again.
+    fn save_session(&self) {
+        if let Some(store) = &self.store
+            && let Err(err) = store.save(self)
+        {
+            log::warn!(
+                "Could not save the holland2stay session to {}: {}",
+                store.path().display(),
+                err
+            );
+        }
+    }
+}
+
+/// Why a session could not be saved or restored.
+#[derive(Debug, thiserror::Error)]
+pub enum SessionStoreError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+
+    #[error("Could not (de)serialize the cookies: {0}")]
+    Cookies(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
+
+    /// Without credentials a restored session could not be renewed, so it is not saved.
+    #[error("The login has no credentials to renew the session with")]
+    NoCredentials,
+
+    /// The file the session is only saved alongside was removed, see [`SessionStore::while_exists`].
+    #[error("The session is not saved, {} does not exist", .0.display())]
+    Revoked(PathBuf),
+}
+
+/// A session as it is saved on disk.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredSession {
+    base_url: String,
+    username: String,
+    bearer_token: String,
+    /// Rfc3339.
+    expires: Option<String>,
+    /// All cookies of the session, in the json format of `cookie_store`.
+    cookies: serde_json::Value,
+}
+
+/// A file the cookies and bearer token of a login are kept in between runs, so that a restart does
+/// not have to log in again. Only the owner of the file can read it, and it can be encrypted as well.
+///
+/// The bot itself only queries the public api and has no account to log in to, so sessions are
+/// only kept for the accounts users linked, see [`crate::accounts::LinkedAccounts::login`]. The
+/// bot restores them all at startup.
+#[derive(Clone, Debug)]
+pub struct SessionStore {
+    path: PathBuf,
+    key: Option<CredentialsKey>,
+    required_path: Option<PathBuf>,
+}
+
+impl SessionStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SessionStore {
+            path: path.into(),
+            key: None,
+            required_path: None,
+        }
+    }
+
+    /// A store that encrypts the session with `key`.
+    pub fn encrypted(path: impl Into<PathBuf>, key: &CredentialsKey) -> Self {
+        SessionStore {
+            path: path.into(),
+            key: Some(key.clone()),
+            required_path: None,
+        }
+    }
+
+    /// Only saves the session while the file at `required_path` exists. Whoever removes that file
+    /// has to remove the session after it, then a save running at the same time never leaves a
+    /// session behind.
+    pub fn while_exists(mut self, required_path: impl Into<PathBuf>) -> Self {
+        self.required_path = Some(required_path.into());
+        self
+    }
+
+    fn check_required_path(&self) -> Result<(), SessionStoreError> {
+        match &self.required_path {
+            Some(required_path) if !required_path.exists() => {
+                Err(SessionStoreError::Revoked(required_path.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the stored session with the one of `login`.
+    pub fn save(&self, login: &Login) -> Result<(), SessionStoreError> {
+        let credentials = login
+            .credentials
+            .as_ref()
+            .ok_or(SessionStoreError::NoCredentials)?;
+        self.check_required_path()?;
+        let mut cookies = vec![];
+        {
+            let cookie_store = login.cookies.lock().expect("cookie store lock poisoned");
+            cookie_store::serde::json::save_incl_expired_and_nonpersistent(
+                &cookie_store,
+                &mut cookies,
+            )
+            .map_err(SessionStoreError::Cookies)?;
+        }
+        let session = StoredSession {
+            base_url: credentials.base_url.to_string(),
+            username: credentials.auth.username.clone(),
+            bearer_token: login.bearer_token.clone(),
+            expires: login.expires.map(|expires| expires.to_rfc3339()),
+            cookies: serde_json::from_slice(&cookies)?,
+        };
+        let mut contents = serde_json::to_vec(&session)?;
+        if let Some(key) = &self.key {
+            contents = encrypt(key, &contents)?;
+        }
+        write_private_file(&self.path, &contents)?;
+        // If the required file was removed while writing, the session may have been removed
+        // before it was written.
+        if let Err(err) = self.check_required_path() {
+            self.remove()?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// The stored session, or `None` if nothing was stored yet.
+    fn load(&self) -> Result<Option<StoredSession>, SessionStoreError> {
+        match fs::read(&self.path) {
+            Ok(mut contents) => {
+                if let Some(key) = &self.key {
+                    contents = decrypt(key, &contents)?;
+                }
+                Ok(Some(serde_json::from_slice(&contents)?))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Deletes the stored session, if there is one.
+    pub fn remove(&self) -> Result<(), SessionStoreError> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Replaces the file at `path` with one only its owner can read. The contents are written to a new
+/// file that is moved in place, so that a crash never leaves half a file behind.
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp_path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp_path)?.write_all(contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Why logging in to holland2stay failed.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("holland2stay did not accept the username and password")]
+    InvalidCredentials,
+
+    #[error("holland2stay rejected the csrf token")]
+    CsrfMismatch,
+
+    #[error("holland2stay asked to solve a captcha")]
+    Captcha,
+
+    #[error("holland2stay is rate limiting logins")]
+    RateLimited { retry_after: Option<Duration> },
+
+    /// The login flow did not go the way it used to, e.g. a response is missing a field.
+    #[error("The holland2stay login flow changed: {0}")]
+    SiteChanged(String),
+
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+impl AuthError {
+    /// Errors worth trying to log in again for, unlike rejected credentials.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AuthError::RateLimited { .. } => true,
+            AuthError::Request(err) => {
+                RetryPolicy::is_retryable_error(err)
+                    || err.status().is_some_and(RetryPolicy::is_retryable_status)
+            }
+            _ => false,
+        }
+    }
+
+    /// What to tell the user who tried to log in.
+    pub fn user_message(&self) -> String {
+        match self {
+            AuthError::InvalidCredentials => {
+                "holland2stay did not accept your email address and password, check them and try again.".to_string()
+            }
+            AuthError::CsrfMismatch => {
+                "holland2stay rejected the login attempt, please try again.".to_string()
+            }
+            AuthError::Captcha => {
+                "holland2stay wants to make sure you are not a robot. Log in on holland2stay.com yourself once and try again later.".to_string()
+            }
+            AuthError::RateLimited {
+                retry_after: Some(retry_after),
+            } => format!(
+                "There were too many login attempts, try again in {} minutes.",
+                retry_after.as_secs().div_ceil(60)
+            ),
+            AuthError::RateLimited { retry_after: None } => {
+                "There were too many login attempts, try again later.".to_string()
+            }
+            AuthError::SiteChanged(_) => {
+                "Logging in to holland2stay does not work at the moment, the bot needs an update.".to_string()
+            }
+            AuthError::Request(_) => {
+                "Could not reach holland2stay, try again later.".to_string()
+            }
+        }
+    }
+}
+
+/// The part of the session the api needs.
+struct Session {
+    bearer_token: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+pub fn holland2stay_base_url() -> Url {
     Url::parse("https://holland2stay.com").expect("could not parse holland2stay.com")
 }
 
+/// A client with its own cookie store. Redirects are not followed, since where the login redirects
+/// to tells whether it succeeded.
 pub fn build_client() -> Client {
-    let cookie_store = Arc::new(cookie::Jar::default());
+    build_client_with_cookies(Arc::default())
+}
+
+fn build_client_with_cookies(cookies: Arc<CookieStoreMutex>) -> Client {
     Client::builder()
-        .cookie_provider(cookie_store.clone())
+        .cookie_provider(cookies)
+        .redirect(redirect::Policy::none())
         .build()
         .expect("Could not build http client")
 }
 
-async fn initiate_session(client: &Client) -> Result<(), reqwest::Error> {
-    let url = holland2stay_base_url()
-        .join("/api/auth/session")
+/// Turns the responses that block a login into the matching error.
+fn check_status(response: Response) -> Result<Response, AuthError> {
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(AuthError::RateLimited {
+            retry_after: retry_after(response.headers()),
+        });
+    }
+    if response
+        .headers()
+        .get("cf-mitigated")
+        .is_some_and(|value| value == "challenge")
+    {
+        return Err(AuthError::Captcha);
+    }
+    Ok(response.error_for_status()?)
+}
+
+async fn initiate_session(client: &Client, base_url: &Url) -> Result<(), AuthError> {
+    let url = base_url
+        .join("api/auth/session")
         .expect("Could not parse session url");
-    let _ = client.get(url).send().await?.error_for_status()?;
+    check_status(client.get(url).send().await?)?;
     Ok(())
 }
 
-async fn get_csfr_token(client: &Client) -> Result<String, Holland2StayError> {
-    let url = holland2stay_base_url()
+async fn get_csfr_token(client: &Client, base_url: &Url) -> Result<String, AuthError> {
+    let url = base_url
         .join("api/auth/csrf")
         .expect("could not parse csfr url");
-    let response = client
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
+    let response = check_status(client.get(url).send().await?)?
         .json::<serde_json::Value>()
         .await?;
     fn parse_response(response: &serde_json::Value) -> Option<String> {
@@ -57,11 +432,39 @@ async fn get_csfr_token(client: &Client) -> Result<String, Holland2StayError> {
         )
     }
     parse_response(&response)
-        .ok_or_else(|| Holland2StayError::ConversionError("Could not parse csfr token".to_string()))
+        .ok_or_else(|| AuthError::SiteChanged("the csrf response has no csrfToken".to_string()))
+}
+
+/// Checks where the credentials callback sent the browser: back to the website if the login
+/// succeeded, or to a page whose query says what went wrong.
+fn check_callback_url(url: &Url) -> Result<(), AuthError> {
+    let query_value = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    if query_value("csrf").as_deref() == Some("true") {
+        return Err(AuthError::CsrfMismatch);
+    }
+    match query_value("error").as_deref() {
+        None => Ok(()),
+        Some("CredentialsSignin") => Err(AuthError::InvalidCredentials),
+        Some("MissingCSRF") => Err(AuthError::CsrfMismatch),
+        Some(error) if error.to_lowercase().contains("captcha") => Err(AuthError::Captcha),
+        Some(error) => Err(AuthError::SiteChanged(format!(
+            "unexpected login error {}",
+            error
+        ))),
+    }
 }
 
-async fn login(client: &Client, auth: &Auth, token: &str) -> Result<String, Holland2StayError> {
-    let url = holland2stay_base_url()
+async fn login(
+    client: &Client,
+    base_url: &Url,
+    auth: &Auth,
+    token: &str,
+) -> Result<Session, AuthError> {
+    let url = base_url
         .join("api/auth/callback/credentials")
         .expect("could not parse login url");
     let form_body = HashMap::from([
@@ -70,21 +473,45 @@ async fn login(client: &Client, auth: &Auth, token: &str) -> Result<String, Holl
         ("csrfToken", token),
     ]);
 
-    let _ = client
-        .post(url)
-        .form(&form_body)
-        .send()
-        .await?
-        .error_for_status()?;
+    let response = client.post(url).form(&form_body).send().await?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Err(AuthError::InvalidCredentials);
+    }
+    let response = check_status(response)?;
+    // The callback redirects, unless it was asked for json, in which case it returns the url.
+    let redirect = if response.status().is_redirection() {
+        response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string)
+    } else {
+        response
+            .json::<serde_json::Value>()
+            .await?
+            .get("url")
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string)
+    };
+    let redirect = redirect
+        .and_then(|redirect| base_url.join(&redirect).ok())
+        .ok_or_else(|| {
+            AuthError::SiteChanged("the login did not say where to go next".to_string())
+        })?;
+    check_callback_url(&redirect)?;
 
-    let url = holland2stay_base_url()
+    get_session(client, base_url).await?.ok_or_else(|| {
+        AuthError::SiteChanged("the session has no access token after logging in".to_string())
+    })
+}
+
+/// The session the cookies of `client` belong to, or `None` if they do not belong to a logged in
+/// session.
+async fn get_session(client: &Client, base_url: &Url) -> Result<Option<Session>, AuthError> {
+    let url = base_url
         .join("api/auth/session")
         .expect("could not parse session url");
-    let response = client
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
+    let response = check_status(client.get(url).send().await?)?
         .json::<serde_json::Value>()
         .await?;
     fn parse_bearer_token(response: &serde_json::Value) -> Option<String> {
@@ -96,57 +523,447 @@ async fn login(client: &Client, auth: &Auth, token: &str) -> Result<String, Holl
                 .to_string(),
         )
     }
-    parse_bearer_token(&response).ok_or_else(|| {
-        Holland2StayError::ConversionError(
-            "Could not parse json session response into bearer token".to_string(),
-        )
+    let Some(bearer_token) = parse_bearer_token(&response) else {
+        return Ok(None);
+    };
+    let expires = response
+        .get("expires")
+        .and_then(serde_json::Value::as_str)
+        .and_then(|expires| DateTime::parse_from_rfc3339(expires).ok())
+        .map(|expires| expires.with_timezone(&Utc));
+    Ok(Some(Session {
+        bearer_token,
+        expires,
+    }))
+}
+
+pub async fn login_holland2stay(auth: &Auth) -> Result<Login, AuthError> {
+    login_holland2stay_at(&holland2stay_base_url(), auth).await
+}
+
+/// Logs in to the holland2stay website hosted at `base_url`.
+pub async fn login_holland2stay_at(base_url: &Url, auth: &Auth) -> Result<Login, AuthError> {
+    let cookies = Arc::new(CookieStoreMutex::default());
+    let client = build_client_with_cookies(cookies.clone());
+    initiate_session(&client, base_url).await?;
+    let csfr_token = get_csfr_token(&client, base_url).await?;
+    let session = login(&client, base_url, auth, &csfr_token).await?;
+    Ok(Login {
+        client,
+        bearer_token: session.bearer_token,
+        expires: session.expires,
+        credentials: Some(Credentials {
+            base_url: base_url.clone(),
+            auth: auth.clone(),
+        }),
+        cookies,
+        store: None,
     })
 }
 
-pub async fn login_holland2stay(auth: &Auth) -> Result<Login, Holland2StayError> {
-    let client = build_client();
-    initiate_session(&client).await?;
-    let csfr_token = get_csfr_token(&client).await?;
-    let bearer_token = login(&client, auth, &csfr_token).await?;
-    Ok(Login::new(client, bearer_token))
+pub async fn restore_or_login_holland2stay(
+    auth: &Auth,
+    store: SessionStore,
+) -> Result<Login, AuthError> {
+    restore_or_login_holland2stay_at(&holland2stay_base_url(), auth, store).await
+}
+
+/// Continues the session saved in `store` if holland2stay still accepts it, and logs in otherwise.
+/// The session is saved to `store` again whenever it is renewed.
+pub async fn restore_or_login_holland2stay_at(
+    base_url: &Url,
+    auth: &Auth,
+    store: SessionStore,
+) -> Result<Login, AuthError> {
+    let mut login = match restore_session(base_url, auth, &store).await {
+        Some(login) => login,
+        None => login_holland2stay_at(base_url, auth).await?,
+    };
+    login.store = Some(store);
+    login.save_session();
+    Ok(login)
+}
+
+/// The session saved in `store`, if it was saved for the same user and holland2stay still accepts
+/// it.
+async fn restore_session(base_url: &Url, auth: &Auth, store: &SessionStore) -> Option<Login> {
+    let stored = match store.load() {
+        Ok(stored) => stored?,
+        Err(err) => {
+            log::warn!(
+                "Could not read the holland2stay session from {}: {}",
+                store.path().display(),
+                err
+            );
+            return None;
+        }
+    };
+    if stored.base_url != base_url.as_str() || stored.username != auth.username {
+        return None;
+    }
+    let cookie_store = serde_json::to_vec(&stored.cookies)
+        .map_err(SessionStoreError::from)
+        .and_then(|cookies| {
+            cookie_store::serde::json::load(cookies.as_slice()).map_err(SessionStoreError::Cookies)
+        });
+    let cookies: CookieStore = match cookie_store {
+        Ok(cookies) => cookies,
+        Err(err) => {
+            log::warn!("Could not restore the holland2stay cookies: {}", err);
+            return None;
+        }
+    };
+    let cookies = Arc::new(CookieStoreMutex::new(cookies));
+    let client = build_client_with_cookies(cookies.clone());
+    let session = match get_session(&client, base_url).await {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            log::info!("The stored holland2stay session expired, logging in again");
+            return None;
+        }
+        Err(err) => {
+            log::warn!(
+                "Could not check the stored holland2stay session: {}, logging in again",
+                err
+            );
+            return None;
+        }
+    };
+    if session.bearer_token != stored.bearer_token {
+        log::debug!("holland2stay issued a new bearer token for the stored session");
+    }
+    Some(Login {
+        client,
+        bearer_token: session.bearer_token,
+        expires: session.expires,
+        credentials: Some(Credentials {
+            base_url: base_url.clone(),
+            auth: auth.clone(),
+        }),
+        cookies,
+        store: None,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_server::{self, FakeHolland2Stay, auth};
+    use wiremock::{
+        Mock, ResponseTemplate,
+        matchers::{method, path},
+    };
 
     #[tokio::test]
     async fn test_initiate_session() {
+        let server = FakeHolland2Stay::start().await;
         let client = build_client();
-        initiate_session(&client).await.unwrap();
-        // if let Some(cookie) = session.cookie_store.cookies(&holland2stay_base_url()) {
-        //     println!("cookie: {:?}", cookie);
-        // }
+        initiate_session(&client, &server.base_url()).await.unwrap();
     }
 
     #[tokio::test]
     async fn test_get_csfr_token() {
+        let server = FakeHolland2Stay::start().await;
         let client = build_client();
-        initiate_session(&client).await.unwrap();
-        let token = get_csfr_token(&client).await.unwrap();
-        println!("token: {token}")
+        initiate_session(&client, &server.base_url()).await.unwrap();
+        let token = get_csfr_token(&client, &server.base_url()).await.unwrap();
+        assert_eq!(token, test_server::fixture("csrf.json")["csrfToken"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_csfr_token_missing() {
+        let server = FakeHolland2Stay::empty().await;
+        Mock::given(method("GET"))
+            .and(path("/api/auth/csrf"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .mount(server.mock_server())
+            .await;
+        let error = get_csfr_token(&build_client(), &server.base_url())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AuthError::SiteChanged(_)));
     }
 
     #[tokio::test]
     async fn test_login() {
+        let server = FakeHolland2Stay::start().await;
         let client = build_client();
-        initiate_session(&client).await.unwrap();
-        let csfr_token = get_csfr_token(&client).await.unwrap();
-        let bearer_token = login(
-            &client,
-            &Auth::new(
-                "matteo@meluzzi.com".to_string(),
-                r#"4Td(\@)]vSFot^15]\jC/ir(i,iW<}H6fpLx9i`wPF"#.to_string(),
-            ),
-            &csfr_token,
+        let csfr_token = get_csfr_token(&client, &server.base_url()).await.unwrap();
+        let session = login(&client, &server.base_url(), &auth(), &csfr_token)
+            .await
+            .unwrap();
+        assert_eq!(
+            session.bearer_token,
+            test_server::fixture("session.json")["accessToken"]
+        );
+        assert!(session.expires.is_some_and(|expires| expires > Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn test_login_holland2stay() {
+        let server = FakeHolland2Stay::start().await;
+        let login = login_holland2stay_at(&server.base_url(), &auth())
+            .await
+            .unwrap();
+        assert_eq!(
+            login.bearer_token(),
+            test_server::fixture("session.json")["accessToken"]
+        );
+
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let callback = requests
+            .iter()
+            .find(|request| request.url.path() == "/api/auth/callback/credentials")
+            .unwrap();
+        let form = String::from_utf8_lossy(&callback.body);
+        assert!(form.contains("username=jane%40example.com"));
+        assert!(form.contains("password=hunter2"));
+        let csrf_token = test_server::fixture("csrf.json")["csrfToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(form.contains(&format!("csrfToken={}", csrf_token)));
+    }
+
+    #[test]
+    fn test_needs_refresh() {
+        let now = Utc::now();
+        let mut login = Login::new(build_client(), "token".to_string());
+        assert!(!login.needs_refresh(now));
+        login.expires = Some(now + TimeDelta::hours(1));
+        assert!(!login.needs_refresh(now));
+        login.expires = Some(now + TimeDelta::minutes(1));
+        assert!(login.needs_refresh(now));
+    }
+
+    #[tokio::test]
+    async fn test_relogin() {
+        let server = FakeHolland2Stay::start().await;
+        let mut login = login_holland2stay_at(&server.base_url(), &auth())
+            .await
+            .unwrap();
+        // The first attempt fails on a server error, the second one goes through.
+        Mock::given(method("GET"))
+            .and(path("/api/auth/csrf"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let retry_policy = RetryPolicy {
+            initial_backoff: std::time::Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        login.relogin(&retry_policy).await.unwrap();
+        assert_eq!(
+            login.bearer_token(),
+            test_server::fixture("session.json")["accessToken"]
+        );
+        let requests = server.mock_server().received_requests().await.unwrap();
+        let callbacks = requests
+            .iter()
+            .filter(|request| request.url.path() == "/api/auth/callback/credentials")
+            .count();
+        assert_eq!(callbacks, 2);
+    }
+
+    #[tokio::test]
+    async fn test_relogin_without_credentials() {
+        let mut login = Login::new(build_client(), "token".to_string());
+        let error = login.relogin(&RetryPolicy::no_retries()).await.unwrap_err();
+        assert!(matches!(error, Holland2StayError::SessionExpired));
+    }
+
+    #[tokio::test]
+    async fn test_login_with_wrong_credentials() {
+        let server = FakeHolland2Stay::start().await;
+        let error = login_holland2stay_at(
+            &server.base_url(),
+            &Auth::new("jane@example.com".to_string(), "wrong".to_string()),
         )
         .await
+        .err()
         .unwrap();
-        println!("bearer token: {bearer_token}")
+        assert!(matches!(error, AuthError::InvalidCredentials));
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_login_with_csrf_mismatch() {
+        let server = FakeHolland2Stay::start().await;
+        let client = build_client();
+        initiate_session(&client, &server.base_url()).await.unwrap();
+        let error = login(&client, &server.base_url(), &auth(), "stale")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, AuthError::CsrfMismatch));
+    }
+
+    #[tokio::test]
+    async fn test_login_rate_limited() {
+        let server = FakeHolland2Stay::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/callback/credentials"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "120"))
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let error = login_holland2stay_at(&server.base_url(), &auth())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            AuthError::RateLimited {
+                retry_after: Some(retry_after)
+            } if retry_after == Duration::from_secs(120)
+        ));
+        assert_eq!(
+            error.user_message(),
+            "There were too many login attempts, try again in 2 minutes."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_without_session() {
+        let server = FakeHolland2Stay::empty().await;
+        server.mount_csrf().await;
+        server.mount_session().await;
+        // The login seems to succeed, but does not set the session cookie.
+        Mock::given(method("POST"))
+            .and(path("/api/auth/callback/credentials"))
+            .respond_with(ResponseTemplate::new(302).insert_header("location", "/"))
+            .mount(server.mock_server())
+            .await;
+        let error = login_holland2stay_at(&server.base_url(), &auth())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, AuthError::SiteChanged(_)));
+    }
+
+    fn count_logins(requests: &[wiremock::Request]) -> usize {
+        requests
+            .iter()
+            .filter(|request| request.url.path() == "/api/auth/callback/credentials")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_restore_session() {
+        let server = FakeHolland2Stay::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(dir.path().join("session.json"));
+        let login = restore_or_login_holland2stay_at(&server.base_url(), &auth(), store.clone())
+            .await
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(store.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let restored = restore_or_login_holland2stay_at(&server.base_url(), &auth(), store)
+            .await
+            .unwrap();
+        assert_eq!(restored.bearer_token(), login.bearer_token());
+        assert!(restored.expires().is_some());
+        let requests = server.mock_server().received_requests().await.unwrap();
+        assert_eq!(count_logins(&requests), 1);
+    }
+
+    #[tokio::test]
+    async fn test_restore_expired_session() {
+        let server = FakeHolland2Stay::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(dir.path().join("session.json"));
+        restore_or_login_holland2stay_at(&server.base_url(), &auth(), store.clone())
+            .await
+            .unwrap();
+        // holland2stay no longer knows the stored session cookie.
+        Mock::given(method("GET"))
+            .and(path("/api/auth/session"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(test_server::fixture("session_anonymous.json")),
+            )
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(server.mock_server())
+            .await;
+        let login = restore_or_login_holland2stay_at(&server.base_url(), &auth(), store)
+            .await
+            .unwrap();
+        assert_eq!(
+            login.bearer_token(),
+            test_server::fixture("session.json")["accessToken"]
+        );
+        let requests = server.mock_server().received_requests().await.unwrap();
+        assert_eq!(count_logins(&requests), 2);
+    }
+
+    #[test]
+    fn test_save_session_without_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(dir.path().join("session.json"));
+        let mut login = Login::new(build_client(), "token".to_string());
+        assert!(matches!(
+            login.persist_to(store.clone()),
+            Err(SessionStoreError::NoCredentials)
+        ));
+        assert!(!store.path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_save_session_while_exists() {
+        let server = FakeHolland2Stay::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let required_path = dir.path().join("credentials.json");
+        let store = SessionStore::new(dir.path().join("session.json")).while_exists(&required_path);
+        let login = login_holland2stay_at(&server.base_url(), &auth())
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.save(&login),
+            Err(SessionStoreError::Revoked(_))
+        ));
+        assert!(!store.path().exists());
+        fs::write(&required_path, "{}").unwrap();
+        store.save(&login).unwrap();
+        assert!(store.path().exists());
+    }
+
+    #[test]
+    fn test_remove_session_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(dir.path().join("session.json"));
+        store.remove().unwrap();
+        fs::write(store.path(), "{}").unwrap();
+        store.remove().unwrap();
+        assert!(!store.path().exists());
+    }
+
+    #[test]
+    fn test_check_callback_url() {
+        let check = |url: &str| check_callback_url(&Url::parse(url).unwrap());
+        assert!(check("https://holland2stay.com/").is_ok());
+        assert!(matches!(
+            check("https://holland2stay.com/api/auth/error?error=CredentialsSignin"),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            check("https://holland2stay.com/api/auth/signin?csrf=true"),
+            Err(AuthError::CsrfMismatch)
+        ));
+        assert!(matches!(
+            check("https://holland2stay.com/api/auth/error?error=InvalidCaptcha"),
+            Err(AuthError::Captcha)
+        ));
+        assert!(matches!(
+            check("https://holland2stay.com/api/auth/error?error=Configuration"),
+            Err(AuthError::SiteChanged(_))
+        ));
     }
 }
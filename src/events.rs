@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+
+use crate::api::{Availability, House, Money, Sku, format_date, is_some_or_unknown_str};
+
+/// The kinds of changes to listings users can be notified about.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, derive_more::Display)]
+pub enum EventKind {
+    #[display("new")]
+    Appeared,
+    #[display("booked")]
+    Disappeared,
+    #[display("price")]
+    PriceChanged,
+    #[display("startdate")]
+    StartDateChanged,
+    #[display("lottery")]
+    LotterySubscribersChanged,
+    #[display("availability")]
+    AvailabilityChanged,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        EventKind::Appeared,
+        EventKind::Disappeared,
+        EventKind::PriceChanged,
+        EventKind::StartDateChanged,
+        EventKind::LotterySubscribersChanged,
+        EventKind::AvailabilityChanged,
+    ];
+
+    /// Parses the name an event kind is displayed as, ignoring case and surrounding whitespace.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        Self::ALL.into_iter().find(|kind| kind.to_string() == name)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            EventKind::Appeared => "a new house is listed",
+            EventKind::Disappeared => "a house is no longer listed, usually because it was booked",
+            EventKind::PriceChanged => "the price of a house changes",
+            EventKind::StartDateChanged => "the start date of a house changes",
+            EventKind::LotterySubscribersChanged => {
+                "the number of people in the lottery for a house changes"
+            }
+            EventKind::AvailabilityChanged => "a house switches between direct booking and lottery",
+        }
+    }
+}
+
+/// A change to a listing between two polls. Every event carries the latest snapshot of the house.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListingEvent {
+    Appeared(House),
+    Disappeared(House),
+    PriceChanged {
+        house: House,
+        old: Option<Money>,
+    },
+    StartDateChanged {
+        house: House,
+        old: Option<NaiveDate>,
+    },
+    LotterySubscribersChanged {
+        house: House,
+        old: Option<u32>,
+    },
+    AvailabilityChanged {
+        house: House,
+        old: Option<Availability>,
+    },
+}
+
+impl ListingEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            ListingEvent::Appeared(_) => EventKind::Appeared,
+            ListingEvent::Disappeared(_) => EventKind::Disappeared,
+            ListingEvent::PriceChanged { .. } => EventKind::PriceChanged,
+            ListingEvent::StartDateChanged { .. } => EventKind::StartDateChanged,
+            ListingEvent::LotterySubscribersChanged { .. } => EventKind::LotterySubscribersChanged,
+            ListingEvent::AvailabilityChanged { .. } => EventKind::AvailabilityChanged,
+        }
+    }
+
+    pub fn house(&self) -> &House {
+        match self {
+            ListingEvent::Appeared(house) | ListingEvent::Disappeared(house) => house,
+            ListingEvent::PriceChanged { house, .. }
+            | ListingEvent::StartDateChanged { house, .. }
+            | ListingEvent::LotterySubscribersChanged { house, .. }
+            | ListingEvent::AvailabilityChanged { house, .. } => house,
+        }
+    }
+}
+
+impl std::fmt::Display for ListingEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListingEvent::Appeared(house) => write!(f, "I found a new house! {}", house),
+            ListingEvent::Disappeared(house) => {
+                write!(f, "This house is no longer available: {}", house)
+            }
+            ListingEvent::PriceChanged { house, old } => write!(
+                f,
+                "The price changed from {} to {}: {}",
+                is_some_or_unknown_str(old),
+                is_some_or_unknown_str(&house.price),
+                house
+            ),
+            ListingEvent::StartDateChanged { house, old } => write!(
+                f,
+                "The start date changed from {} to {}: {}",
+                is_some_or_unknown_str(&old.map(format_date)),
+                is_some_or_unknown_str(&house.start_date.map(format_date)),
+                house
+            ),
+            ListingEvent::LotterySubscribersChanged { house, old } => write!(
+                f,
+                "The number of lottery subscribers changed from {} to {}: {}",
+                is_some_or_unknown_str(old),
+                is_some_or_unknown_str(&house.current_lottery_subscribers),
+                house
+            ),
+            ListingEvent::AvailabilityChanged { house, old } => write!(
+                f,
+                "The availability changed from {} to {}: {}",
+                is_some_or_unknown_str(old),
+                is_some_or_unknown_str(&house.availability),
+                house
+            ),
+        }
+    }
+}
+
+/// The events that turn the listings `old` into `new`, ordered by sku and then by kind.
+pub fn diff_listings(old: &HashMap<Sku, House>, new: &HashMap<Sku, House>) -> Vec<ListingEvent> {
+    let mut events = Vec::new();
+    for (sku, house) in new {
+        let Some(old_house) = old.get(sku) else {
+            events.push(ListingEvent::Appeared(house.clone()));
+            continue;
+        };
+        if old_house.price != house.price {
+            events.push(ListingEvent::PriceChanged {
+                house: house.clone(),
+                old: old_house.price.clone(),
+            });
+        }
+        if old_house.start_date != house.start_date {
+            events.push(ListingEvent::StartDateChanged {
+                house: house.clone(),
+                old: old_house.start_date,
+            });
+        }
+        if old_house.current_lottery_subscribers != house.current_lottery_subscribers {
+            events.push(ListingEvent::LotterySubscribersChanged {
+                house: house.clone(),
+                old: old_house.current_lottery_subscribers,
+            });
+        }
+        if old_house.availability != house.availability {
+            events.push(ListingEvent::AvailabilityChanged {
+                house: house.clone(),
+                old: old_house.availability,
+            });
+        }
+    }
+    events.extend(
+        old.iter()
+            .filter(|(sku, _)| !new.contains_key(sku))
+            .map(|(_, house)| ListingEvent::Disappeared(house.clone())),
+    );
+    events.sort_by(|a, b| (&a.house().sku, a.kind()).cmp(&(&b.house().sku, b.kind())));
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{City, CostBreakdown};
+
+    fn house(sku: &str) -> House {
+        House {
+            name: format!("Kruisplein {}", sku),
+            sku: Sku::from(sku.to_string()),
+            url: None,
+            city: City {
+                id: "25".parse().unwrap(),
+                name: "Rotterdam".to_string(),
+            },
+            building_name: None,
+            living_area: None,
+            rooms: None,
+            finishing: None,
+            resident_type: None,
+            maximum_number_of_persons: None,
+            energy_label: None,
+            floor: None,
+            minimum_stay_months: None,
+            price: Some(Money {
+                amount: "731".parse().unwrap(),
+                currency: "EUR".to_string(),
+            }),
+            costs: CostBreakdown::default(),
+            allowance_price: None,
+            price_analysis_text: None,
+            availability: Some(Availability::DirectBooking),
+            available_from: None,
+            start_date: NaiveDate::from_ymd_opt(2025, 3, 5),
+            current_lottery_subscribers: None,
+            contract_type: None,
+            offer_text: None,
+            offer_text_two: None,
+        }
+    }
+
+    fn listings(houses: impl IntoIterator<Item = House>) -> HashMap<Sku, House> {
+        houses
+            .into_iter()
+            .map(|house| (house.sku.clone(), house))
+            .collect()
+    }
+
+    #[test]
+    fn test_event_kind_names() {
+        for kind in EventKind::ALL {
+            assert_eq!(EventKind::from_name(&kind.to_string()), Some(kind));
+        }
+        assert_eq!(
+            EventKind::from_name(" Price "),
+            Some(EventKind::PriceChanged)
+        );
+        assert_eq!(EventKind::from_name("rent"), None);
+    }
+
+    #[test]
+    fn test_unchanged_listings() {
+        let houses = listings([house("A"), house("B")]);
+        assert!(diff_listings(&houses, &houses).is_empty());
+    }
+
+    #[test]
+    fn test_diff_listings() {
+        let old = listings([house("A"), house("B"), house("C")]);
+        let mut changed = house("B");
+        changed.price.as_mut().unwrap().amount = "699".parse().unwrap();
+        changed.start_date = NaiveDate::from_ymd_opt(2025, 4, 1);
+        changed.current_lottery_subscribers = Some(12);
+        changed.availability = Some(Availability::Lottery);
+        // Fields that are not tracked do not produce events.
+        changed.offer_text = Some("First month free".to_string());
+        let new = listings([house("A"), changed, house("D")]);
+
+        let events = diff_listings(&old, &new);
+        let kinds: Vec<(&str, EventKind)> = events
+            .iter()
+            .map(|event| (event.house().sku.as_str(), event.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("B", EventKind::PriceChanged),
+                ("B", EventKind::StartDateChanged),
+                ("B", EventKind::LotterySubscribersChanged),
+                ("B", EventKind::AvailabilityChanged),
+                ("C", EventKind::Disappeared),
+                ("D", EventKind::Appeared),
+            ]
+        );
+        assert_eq!(
+            events[0],
+            ListingEvent::PriceChanged {
+                house: new[&Sku::from("B".to_string())].clone(),
+                old: old[&Sku::from("B".to_string())].price.clone(),
+            }
+        );
+        assert!(
+            events[0]
+                .to_string()
+                .starts_with("The price changed from 731.00 EUR to 699.00 EUR: ")
+        );
+        assert!(
+            events[1]
+                .to_string()
+                .starts_with("The start date changed from 5 March 2025 to 1 April 2025: ")
+        );
+    }
+}
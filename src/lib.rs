@@ -0,0 +1,9 @@
+pub mod accounts;
+pub mod allowance;
+pub mod api;
+pub mod auth;
+pub mod events;
+pub mod ngrok;
+
+#[cfg(test)]
+mod test_server;
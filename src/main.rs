@@ -1,13 +1,26 @@
-use api::{City, House};
+use futures::StreamExt;
+use holland2stay_rs::accounts::{AccountError, CredentialsKey, LinkedAccounts};
+use holland2stay_rs::allowance::{
+    ALLOWANCE_RULES_VAR, AllowanceEstimate, AllowanceProfile, AllowanceRules,
+};
+use holland2stay_rs::api::{
+    self, Availability, City, CityRegistry, Holland2StayClient, Holland2StayError, House,
+    LotteryRegistration, MarketStats, Reservation, SearchQuery, Sku,
+};
+use holland2stay_rs::auth::Auth;
+use holland2stay_rs::events::{self, EventKind, ListingEvent};
+use holland2stay_rs::ngrok;
+use itertools::Itertools;
+use reqwest::Url;
 use std::collections::{HashMap, HashSet};
 use std::io::Write;
-use std::sync::Arc;
-use teloxide::{prelude::*, update_listeners::webhooks, utils::command::BotCommands};
+use std::sync::{Arc, LazyLock};
+use teloxide::{
+    prelude::*, types::InputFile, update_listeners::webhooks, utils::command::BotCommands,
+};
 use tokio::signal;
 use tokio::sync::{Mutex, mpsc, mpsc::Receiver};
 
-mod api;
-mod ngrok;
 trait LogErr {
     fn log_err(&self);
 }
@@ -20,6 +33,48 @@ impl<T, E: std::fmt::Display> LogErr for Result<T, E> {
     }
 }
 
+/// Alerts the operator of the bot about problems users can't do anything about, such as a change
+/// to the holland2stay api. The same alert is not sent twice in a row.
+#[derive(Clone, Default)]
+struct OperatorAlerts {
+    chat_id: Option<ChatId>,
+    last_alert: Arc<Mutex<Option<String>>>,
+}
+
+impl OperatorAlerts {
+    fn new(chat_id: Option<ChatId>) -> Self {
+        OperatorAlerts {
+            chat_id,
+            ..Default::default()
+        }
+    }
+
+    /// Alerts the operator if `error` means the api rejected our query.
+    async fn alert_on_error<B: Requester>(&self, bot: &B, error: &Holland2StayError) {
+        if !matches!(error, Holland2StayError::GraphQl(_)) {
+            return;
+        }
+        let Some(chat_id) = self.chat_id else {
+            return;
+        };
+        let alert = format!(
+            "The holland2stay api rejected a query, has it changed? {}",
+            error
+        );
+        let mut last_alert = self.last_alert.lock().await;
+        if last_alert.as_ref() == Some(&alert) {
+            return;
+        }
+        bot.send_message(chat_id, alert.clone()).await.log_err();
+        *last_alert = Some(alert);
+    }
+
+    /// Forgets the last alert, so that the operator is alerted again if the problem comes back.
+    async fn resolve(&self) {
+        *self.last_alert.lock().await = None;
+    }
+}
+
 #[derive(BotCommands, Clone)]
 #[command(
     rename_rule = "lowercase",
@@ -29,28 +84,245 @@ enum Command {
     #[command(description = "Display this text.")]
     Help,
 
-    #[command(description = "Subscribe to a city")]
-    Watch(City),
+    #[command(description = "List the cities you can subscribe to")]
+    Cities,
+
+    #[command(
+        description = "Subscribe to a city, to houses you can book directly (default), lottery houses or both: /watch <city> [direct|lottery|both]"
+    )]
+    Watch(String),
 
     #[command(description = "Unscubscribe from a city")]
-    Unwatch(City),
+    Unwatch(String),
 
     #[command(description = "Unscubscribe from all cities")]
     Unsubscribe,
 
     #[command(description = "List subscriptions")]
     Subscriptions,
+
+    #[command(description = "Compare the monthly costs of the houses in a city")]
+    Cost(String),
+
+    #[command(description = "Show the number of listings and their prices in a city")]
+    Stats(String),
+
+    #[command(description = "List the changes to houses you can be notified about")]
+    Events,
+
+    #[command(description = "Get notified about a change to houses in your cities")]
+    Notify(String),
+
+    #[command(description = "Stop getting notified about a change to houses")]
+    Mute(String),
+
+    #[command(description = "Set your age and yearly income to estimate rent allowance")]
+    Profile(String),
+
+    #[command(description = "Estimate the rent allowance for the houses in a city")]
+    Allowance(String),
+
+    #[command(
+        description = "Only get notified about houses you can get rent allowance for (on/off)"
+    )]
+    AllowanceOnly(String),
+
+    #[command(
+        description = "Link your holland2stay account, in a private chat: /link <email> <password>"
+    )]
+    Link(String),
+
+    #[command(description = "Unlink your holland2stay account and delete your credentials")]
+    Unlink,
+
+    #[command(
+        description = "List the reservations and lottery registrations of your holland2stay account"
+    )]
+    Reservations,
+}
+
+// Whenever more than one of these is locked at once, they are locked in the order snapshot,
+// observers, settings, so that the poll and the commands cannot deadlock each other.
+type ObserverMutex = Arc<Mutex<HashMap<ChatId, HashMap<City, WatchMode>>>>;
+/// The latest snapshot of every listing seen, by sku.
+type Houses = HashMap<Sku, House>;
+/// The poll replaces the snapshot as a whole, so commands can read the previous one while a poll
+/// is running.
+type SnapshotMutex = Arc<Mutex<Arc<Snapshot>>>;
+type CitiesMutex = Arc<Mutex<CityRegistry>>;
+type SettingsMutex = Arc<Mutex<HashMap<ChatId, ChatSettings>>>;
+
+/// What the commands share with each other and with the poll.
+#[derive(Clone)]
+struct BotState {
+    observers: ObserverMutex,
+    snapshot: SnapshotMutex,
+    cities: CitiesMutex,
+    settings: SettingsMutex,
+    client: Holland2StayClient,
+    accounts: Option<Arc<LinkedAccounts>>,
 }
 
-type ObserverMutex = Arc<Mutex<HashMap<ChatId, HashSet<City>>>>;
-type HousesMutex = Arc<Mutex<HashSet<House>>>;
+/// What the latest poll found.
+#[derive(Default)]
+struct Snapshot {
+    houses: Houses,
+    /// The main photo of the listed houses, so that it is only fetched once per house.
+    photos: HashMap<Sku, Option<Url>>,
+    /// The market stats of every watched city.
+    stats: HashMap<City, MarketStats>,
+}
+
+/// The rules in the file [`ALLOWANCE_RULES_VAR`] points to, or the defaults. Forced at startup, so
+/// that a broken file is noticed right away.
+static ALLOWANCE_RULES: LazyLock<AllowanceRules> =
+    LazyLock::new(|| match AllowanceRules::from_env() {
+        Some(rules) => rules.expect("Could not load HOLLAND2STAY_ALLOWANCE_RULES"),
+        None => AllowanceRules::default(),
+    });
+
+struct ChatSettings {
+    /// The events the chat opted into, only new houses by default.
+    events: HashSet<EventKind>,
+    allowance_profile: Option<AllowanceProfile>,
+    /// Only notify about houses the chat can likely get rent allowance for.
+    only_allowance_eligible: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            events: HashSet::from([EventKind::Appeared]),
+            allowance_profile: None,
+            only_allowance_eligible: false,
+        }
+    }
+}
+
+impl ChatSettings {
+    fn allowance_estimate(&self, house: &House) -> Option<AllowanceEstimate> {
+        ALLOWANCE_RULES.estimate(self.allowance_profile.as_ref()?, house)
+    }
+
+    fn wants(&self, event: &ListingEvent) -> bool {
+        self.events.contains(&event.kind())
+            && (!self.only_allowance_eligible
+                || self
+                    .allowance_estimate(event.house())
+                    .is_some_and(|estimate| estimate.is_eligible()))
+    }
+}
+
+/// Which houses of a city a chat is subscribed to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, derive_more::Display)]
+enum WatchMode {
+    #[default]
+    #[display("direct")]
+    Direct,
+    #[display("lottery")]
+    Lottery,
+    #[display("both")]
+    Both,
+}
+
+impl WatchMode {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "direct" => Some(WatchMode::Direct),
+            "lottery" => Some(WatchMode::Lottery),
+            "both" => Some(WatchMode::Both),
+            _ => None,
+        }
+    }
+
+    /// Houses whose availability is unknown are included in every mode, rather than missed.
+    fn includes(self, availability: Option<Availability>) -> bool {
+        match (self, availability) {
+            (WatchMode::Both, _) | (_, None) => true,
+            (WatchMode::Direct, Some(availability)) => availability == Availability::DirectBooking,
+            (WatchMode::Lottery, Some(availability)) => availability == Availability::Lottery,
+        }
+    }
+
+    /// A house that moves out of the mode is still reported, so that the chat knows it did.
+    fn wants(self, event: &ListingEvent) -> bool {
+        self.includes(event.house().availability)
+            || matches!(event, ListingEvent::AvailabilityChanged { old, .. } if self.includes(*old))
+    }
+}
+
+/// Splits "<city> [direct|lottery|both]" into the city name and the mode, which defaults to direct.
+fn parse_watch_args(text: &str) -> (&str, WatchMode) {
+    let text = text.trim();
+    text.rsplit_once(char::is_whitespace)
+        .and_then(|(city_name, mode)| Some((city_name.trim_end(), WatchMode::from_name(mode)?)))
+        .unwrap_or((text, WatchMode::default()))
+}
+
+/// The city called `name`, or `None` after telling the chat that there is no such city.
+async fn find_city_or_reply<B: Requester>(
+    bot: &B,
+    chat_id: ChatId,
+    cities: &CitiesMutex,
+    name: &str,
+) -> Result<Option<City>, B::Err> {
+    let city = cities.lock().await.find(name).cloned();
+    if city.is_none() {
+        bot.send_message(
+            chat_id,
+            format!(
+                "I don't know any city called {}. Use /cities to see the cities you can subscribe to.",
+                name
+            ),
+        )
+        .await?;
+    }
+    Ok(city)
+}
+
+async fn allowance_profile(settings: &SettingsMutex, chat_id: ChatId) -> Option<AllowanceProfile> {
+    settings
+        .lock()
+        .await
+        .get(&chat_id)
+        .and_then(|settings| settings.allowance_profile)
+}
+
+/// The allowance profile of the chat, or `None` after asking the chat to set one.
+async fn allowance_profile_or_reply<B: Requester>(
+    bot: &B,
+    chat_id: ChatId,
+    settings: &SettingsMutex,
+) -> Result<Option<AllowanceProfile>, B::Err> {
+    let profile = allowance_profile(settings, chat_id).await;
+    if profile.is_none() {
+        bot.send_message(
+            chat_id,
+            "Set your age and income first with /profile <age> <yearly income>.",
+        )
+        .await?;
+    }
+    Ok(profile)
+}
+
+/// The linked accounts, or `None` after telling the chat that linking is not enabled.
+async fn linked_accounts_or_reply<B: Requester>(
+    bot: &B,
+    chat_id: ChatId,
+    accounts: &Option<Arc<LinkedAccounts>>,
+) -> Result<Option<Arc<LinkedAccounts>>, B::Err> {
+    if accounts.is_none() {
+        bot.send_message(chat_id, "Linking a holland2stay account is not enabled.")
+            .await?;
+    }
+    Ok(accounts.clone())
+}
 
 async fn answer<B: Requester>(
     bot: B,
     msg: Message,
     cmd: Command,
-    observers_mutex: ObserverMutex,
-    houses: HousesMutex,
+    state: BotState,
 ) -> Result<(), B::Err> {
     let chat_id = msg.chat.id;
 
@@ -59,33 +331,62 @@ async fn answer<B: Requester>(
             bot.send_message(chat_id, Command::descriptions().to_string())
                 .await?;
         }
-        Command::Watch(city) => {
-            observers_mutex
+        Command::Cities => {
+            let cities = state.cities.lock().await;
+            if cities.is_empty() {
+                bot.send_message(
+                    chat_id,
+                    "I could not fetch the cities from holland2stay yet, try again later.",
+                )
+                .await?;
+            } else {
+                let cities_list = itertools::join(
+                    cities
+                        .iter()
+                        .map(|entry| format!("{}: {} residences", entry.city, entry.listing_count)),
+                    "\n",
+                );
+                bot.send_message(chat_id, format!("You can subscribe to:\n{}", cities_list))
+                    .await?;
+            }
+        }
+        Command::Watch(args) => {
+            let (city_name, mode) = parse_watch_args(&args);
+            let Some(city) = find_city_or_reply(&bot, chat_id, &state.cities, city_name).await?
+            else {
+                return Ok(());
+            };
+            state
+                .observers
                 .lock()
                 .await
                 .entry(chat_id)
                 .or_default()
-                .insert(city);
+                .insert(city.clone(), mode);
             bot.send_message(
                 chat_id,
-                format!("You are now subscribed to houses in {}.", city),
+                format!("You are now subscribed to houses in {} ({}).", city, mode),
             )
             .await?;
 
-            let houses = houses.lock().await;
-            for house in houses.iter().filter(|house| house.city == city) {
+            let snapshot = state.snapshot.lock().await;
+            for house in snapshot
+                .houses
+                .values()
+                .filter(|house| house.city == city && mode.includes(house.availability))
+            {
                 bot.send_message(chat_id, format!("There is this house: {}", house))
                     .await?;
             }
         }
-        Command::Unwatch(city) => {
-            if observers_mutex
-                .lock()
-                .await
-                .entry(chat_id)
-                .or_default()
-                .remove(&city)
-            {
+        Command::Unwatch(city_name) => {
+            let removed_city = {
+                let mut observers = state.observers.lock().await;
+                let cities = observers.entry(chat_id).or_default();
+                let city = cities.keys().find(|city| city.matches(&city_name)).cloned();
+                city.filter(|city| cities.remove(city).is_some())
+            };
+            if let Some(city) = removed_city {
                 bot.send_message(
                     chat_id,
                     format!("You are now unsubscribed from houses in {}.", city),
@@ -94,14 +395,17 @@ async fn answer<B: Requester>(
             } else {
                 bot.send_message(
                     chat_id,
-                    format!("You were already unsubscribed from houses in {}.", city),
+                    format!(
+                        "You were already unsubscribed from houses in {}.",
+                        city_name
+                    ),
                 )
                 .await?;
             }
         }
         Command::Unsubscribe => {
-            if let Some(cities) = observers_mutex.lock().await.remove(&chat_id) {
-                let cities_list = itertools::join(cities, ", ");
+            if let Some(cities) = state.observers.lock().await.remove(&chat_id) {
+                let cities_list = itertools::join(cities.keys(), ", ");
                 bot.send_message(
                     chat_id,
                     format!("You are now unsubscribed from {}.", cities_list),
@@ -113,8 +417,13 @@ async fn answer<B: Requester>(
             }
         }
         Command::Subscriptions => {
-            if let Some(cities) = observers_mutex.lock().await.get(&chat_id) {
-                let cities_list = itertools::join(cities, ", ");
+            if let Some(cities) = state.observers.lock().await.get(&chat_id) {
+                let cities_list = itertools::join(
+                    cities
+                        .iter()
+                        .map(|(city, mode)| format!("{} ({})", city, mode)),
+                    ", ",
+                );
                 bot.send_message(chat_id, format!("You are subscribed to {}.", cities_list))
                     .await?;
             } else {
@@ -122,17 +431,457 @@ async fn answer<B: Requester>(
                     .await?;
             }
         }
+        Command::Cost(city_name) => {
+            let Some(city) = find_city_or_reply(&bot, chat_id, &state.cities, &city_name).await?
+            else {
+                return Ok(());
+            };
+            let snapshot = state.snapshot.lock().await;
+            let mut houses: Vec<&House> = snapshot
+                .houses
+                .values()
+                .filter(|house| house.city == city)
+                .collect();
+            if houses.is_empty() {
+                bot.send_message(
+                    chat_id,
+                    format!(
+                        "I don't know any houses in {}. Subscribe with /watch to start tracking them.",
+                        city
+                    ),
+                )
+                .await?;
+                return Ok(());
+            }
+            // Houses with unknown costs go last.
+            houses.sort_by_key(|house| (house.costs.total().is_none(), house.costs.total()));
+            let costs_list = itertools::join(
+                houses
+                    .iter()
+                    .map(|house| format!("{}: {}", house.name, house.costs)),
+                "\n",
+            );
+            bot.send_message(
+                chat_id,
+                format!("Monthly costs in {}:\n{}", city, costs_list),
+            )
+            .await?;
+        }
+        Command::Stats(city_name) => {
+            let Some(city) = find_city_or_reply(&bot, chat_id, &state.cities, &city_name).await?
+            else {
+                return Ok(());
+            };
+            let message = match state.snapshot.lock().await.stats.get(&city) {
+                Some(stats) => stats.to_string(),
+                None => format!(
+                    "I have no statistics about {} yet. Subscribe with /watch to start tracking it.",
+                    city
+                ),
+            };
+            bot.send_message(chat_id, message).await?;
+        }
+        Command::Events => {
+            let enabled = state
+                .settings
+                .lock()
+                .await
+                .get(&chat_id)
+                .map(|settings| settings.events.clone())
+                .unwrap_or_else(|| ChatSettings::default().events);
+            let events_list = itertools::join(
+                EventKind::ALL.iter().map(|kind| {
+                    format!(
+                        "{} {}: when {}",
+                        if enabled.contains(kind) {
+                            "[on]"
+                        } else {
+                            "[off]"
+                        },
+                        kind,
+                        kind.description()
+                    )
+                }),
+                "\n",
+            );
+            bot.send_message(
+                chat_id,
+                format!(
+                    "{}\nUse /notify <event> or /mute <event> to change them.",
+                    events_list
+                ),
+            )
+            .await?;
+        }
+        Command::Notify(ref name) | Command::Mute(ref name) => {
+            let notify = matches!(cmd, Command::Notify(_));
+            let Some(kind) = EventKind::from_name(name) else {
+                bot.send_message(
+                    chat_id,
+                    format!(
+                        "There is no event called {}. Use /events to see the events you can be notified about.",
+                        name
+                    ),
+                )
+                .await?;
+                return Ok(());
+            };
+            {
+                let mut settings = state.settings.lock().await;
+                let enabled = &mut settings.entry(chat_id).or_default().events;
+                if notify {
+                    enabled.insert(kind);
+                } else {
+                    enabled.remove(&kind);
+                }
+            }
+            let message = if notify {
+                format!("You will now be notified when {}.", kind.description())
+            } else {
+                format!(
+                    "You will no longer be notified when {}.",
+                    kind.description()
+                )
+            };
+            bot.send_message(chat_id, message).await?;
+        }
+        Command::Profile(text) => {
+            if text.trim().is_empty() {
+                let message = match allowance_profile(&state.settings, chat_id).await {
+                    Some(profile) => format!(
+                        "You are {} years old with a yearly income of {}.",
+                        profile.age, profile.yearly_income
+                    ),
+                    None => "You have not set your age and income yet, use /profile <age> <yearly income>.".to_string(),
+                };
+                bot.send_message(chat_id, message).await?;
+                return Ok(());
+            }
+            let Some(profile) = AllowanceProfile::parse(&text) else {
+                bot.send_message(
+                    chat_id,
+                    "Use /profile <age> <yearly income>, for example /profile 24 28000.",
+                )
+                .await?;
+                return Ok(());
+            };
+            state
+                .settings
+                .lock()
+                .await
+                .entry(chat_id)
+                .or_default()
+                .allowance_profile = Some(profile);
+            bot.send_message(
+                chat_id,
+                "Saved. Use /allowance <city> to estimate your rent allowance.",
+            )
+            .await?;
+        }
+        Command::Allowance(city_name) => {
+            let Some(city) = find_city_or_reply(&bot, chat_id, &state.cities, &city_name).await?
+            else {
+                return Ok(());
+            };
+            // Copy the profile out, the snapshot must not be locked while holding the settings.
+            let Some(profile) = allowance_profile_or_reply(&bot, chat_id, &state.settings).await?
+            else {
+                return Ok(());
+            };
+            let snapshot = state.snapshot.lock().await;
+            let estimates: Vec<String> = snapshot
+                .houses
+                .values()
+                .filter(|house| house.city == city)
+                .filter_map(|house| {
+                    let estimate = ALLOWANCE_RULES.estimate(&profile, house)?;
+                    Some(format!("{}: {}", house.name, estimate))
+                })
+                .collect();
+            let message = if estimates.is_empty() {
+                format!(
+                    "I don't know any houses in {} with rent allowance information.",
+                    city
+                )
+            } else {
+                format!(
+                    "Estimated rent allowance in {}:\n{}\nThis is only an estimate, the Belastingdienst decides on the actual allowance.",
+                    city,
+                    itertools::join(estimates, "\n")
+                )
+            };
+            bot.send_message(chat_id, message).await?;
+        }
+        Command::AllowanceOnly(value) => {
+            let only_eligible = match value.trim().to_lowercase().as_str() {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    bot.send_message(chat_id, "Use /allowanceonly on or /allowanceonly off.")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            // A profile cannot be removed once it is set, so it is still there below.
+            if only_eligible
+                && allowance_profile_or_reply(&bot, chat_id, &state.settings)
+                    .await?
+                    .is_none()
+            {
+                return Ok(());
+            }
+            state
+                .settings
+                .lock()
+                .await
+                .entry(chat_id)
+                .or_default()
+                .only_allowance_eligible = only_eligible;
+            let message = if only_eligible {
+                "You will now only be notified about houses you can likely get rent allowance for."
+            } else {
+                "You will now be notified about all houses."
+            };
+            bot.send_message(chat_id, message).await?;
+        }
+        Command::Link(args) => {
+            // The password should not stay in the chat history, whatever else goes wrong.
+            bot.delete_message(chat_id, msg.id).await.log_err();
+            if !msg.chat.is_private() {
+                // Everyone in the chat can read the password.
+                bot.send_message(
+                    chat_id,
+                    "Only send /link to me in a private chat, never share your password in a group.",
+                )
+                .await?;
+                return Ok(());
+            }
+            let Some(accounts) = linked_accounts_or_reply(&bot, chat_id, &state.accounts).await?
+            else {
+                return Ok(());
+            };
+            // Passwords can contain spaces, so everything after the email is the password.
+            let Some((username, password)) = args
+                .trim()
+                .split_once(char::is_whitespace)
+                .map(|(username, password)| (username, password.trim_start()))
+                .filter(|(_, password)| !password.is_empty())
+            else {
+                bot.send_message(chat_id, "Use /link <email> <password>.")
+                    .await?;
+                return Ok(());
+            };
+            let auth = Auth::new(username.to_string(), password.to_string());
+            let message = match accounts.link(chat_id.0, &auth).await {
+                Ok(_) => format!(
+                    "Linked your holland2stay account {}. Your credentials are stored encrypted, use /unlink to delete them.",
+                    auth.username()
+                ),
+                Err(AccountError::Auth(err)) => {
+                    log::info!(
+                        "Could not link the holland2stay account of {}: {}",
+                        chat_id,
+                        err
+                    );
+                    err.user_message()
+                }
+                Err(err) => {
+                    log::error!("Could not save the credentials of {}: {}", chat_id, err);
+                    "Could not save your credentials, try again later.".to_string()
+                }
+            };
+            bot.send_message(chat_id, message).await?;
+        }
+        Command::Unlink => {
+            let Some(accounts) = linked_accounts_or_reply(&bot, chat_id, &state.accounts).await?
+            else {
+                return Ok(());
+            };
+            let message = match accounts.unlink(chat_id.0).await {
+                Ok(true) => "Unlinked your holland2stay account and deleted your credentials.",
+                Ok(false) => "You have not linked a holland2stay account.",
+                Err(err) => {
+                    log::error!("Could not delete the credentials of {}: {}", chat_id, err);
+                    "Could not delete your credentials, try again later."
+                }
+            };
+            bot.send_message(chat_id, message).await?;
+        }
+        Command::Reservations => {
+            let Some(accounts) = linked_accounts_or_reply(&bot, chat_id, &state.accounts).await?
+            else {
+                return Ok(());
+            };
+            let account_client = match accounts.client(&state.client, chat_id.0).await {
+                Ok(Some(account_client)) => account_client,
+                Ok(None) => {
+                    bot.send_message(
+                        chat_id,
+                        "Link your holland2stay account first: /link <email> <password>",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                Err(AccountError::Auth(err)) => {
+                    log::info!(
+                        "Could not log in to the holland2stay account of {}: {}",
+                        chat_id,
+                        err
+                    );
+                    bot.send_message(chat_id, err.user_message()).await?;
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::error!(
+                        "Could not restore the holland2stay session of {}: {}",
+                        chat_id,
+                        err
+                    );
+                    bot.send_message(
+                        chat_id,
+                        "Could not log in to holland2stay, try again later.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+            let message = match tokio::try_join!(
+                account_client.query_reservations(),
+                account_client.query_lottery_registrations()
+            ) {
+                Ok((reservations, registrations)) => {
+                    reservations_text(&reservations, &registrations)
+                }
+                Err(Holland2StayError::Auth(err)) => {
+                    log::info!(
+                        "Could not log in to the holland2stay account of {} again: {}",
+                        chat_id,
+                        err
+                    );
+                    err.user_message()
+                }
+                Err(err) => {
+                    log::error!("Could not fetch the reservations of {}: {}", chat_id, err);
+                    "Could not fetch your reservations, try again later.".to_string()
+                }
+            };
+            bot.send_message(chat_id, message).await?;
+        }
     };
 
     Ok(())
 }
 
+fn reservations_text(
+    reservations: &[Reservation],
+    registrations: &[LotteryRegistration],
+) -> String {
+    if reservations.is_empty() && registrations.is_empty() {
+        return "You have no reservations or lottery registrations on holland2stay.".to_string();
+    }
+    let mut lines = vec![];
+    if !reservations.is_empty() {
+        lines.push("Your reservations:".to_string());
+        lines.extend(reservations.iter().map(|reservation| {
+            let mut line = format!(
+                "- {}",
+                reservation
+                    .name
+                    .as_deref()
+                    .unwrap_or(reservation.sku.as_str())
+            );
+            if let Some(status) = &reservation.status {
+                line += &format!(" ({})", status);
+            }
+            match (reservation.start_date, reservation.end_date) {
+                (Some(start), Some(end)) => line += &format!(", from {} until {}", start, end),
+                (Some(start), None) => line += &format!(", from {}", start),
+                _ => {}
+            }
+            line
+        }));
+    }
+    if !registrations.is_empty() {
+        lines.push("Your lottery registrations:".to_string());
+        lines.extend(registrations.iter().map(|registration| {
+            let mut line = format!(
+                "- {}",
+                registration
+                    .name
+                    .as_deref()
+                    .unwrap_or(registration.sku.as_str())
+            );
+            if let Some(status) = &registration.status {
+                line += &format!(" ({})", status);
+            }
+            match (registration.position, registration.draw_date) {
+                (Some(position), _) => line += &format!(", drawn at place {}", position),
+                (None, Some(draw_date)) => line += &format!(", drawn on {}", draw_date),
+                (None, None) => {}
+            }
+            line
+        }));
+    }
+    lines.join("\n")
+}
+
+/// How many house details are fetched at the same time for their photos.
+const MAX_CONCURRENT_PHOTO_FETCHES: usize = 4;
+/// How many house details are fetched for their photos in a single poll, the other houses are
+/// announced without a photo.
+const MAX_PHOTO_FETCHES_PER_POLL: usize = 20;
+
+async fn fetch_main_photo(
+    client: &Holland2StayClient,
+    sku: &Sku,
+) -> Result<Option<Url>, Holland2StayError> {
+    let details = client.query_residence_details(sku).await?;
+    Ok(details.main_photo().map(|photo| photo.url.clone()))
+}
+
+/// A notification about a house, and whether it is sent with a photo of the house.
+struct HouseMessage {
+    chat_id: ChatId,
+    sku: Sku,
+    text: String,
+    with_photo: bool,
+}
+
+/// Telegram's limit on the length of a photo caption, in characters.
+const MAX_CAPTION_LENGTH: usize = 1024;
+
+/// Sends `text` as the caption of `photo`, or as a plain message if there is no photo or
+/// telegram could not send it.
+async fn send_house_message<B: Requester>(
+    bot: &B,
+    chat_id: ChatId,
+    text: String,
+    photo: Option<&Url>,
+) {
+    if let Some(photo) = photo.filter(|_| text.chars().count() <= MAX_CAPTION_LENGTH) {
+        match bot
+            .send_photo(chat_id, InputFile::url(photo.clone()))
+            .caption(text.clone())
+            .await
+        {
+            Ok(_) => return,
+            Err(err) => log::warn!("Could not send photo {}: {}", photo, err),
+        }
+    }
+    bot.send_message(chat_id, text).await.log_err();
+}
+
 async fn get_houses_and_notify<Bot: Requester>(
+    client: &Holland2StayClient,
     observers_mutex: &ObserverMutex,
     bot: &mut Bot,
-    old_houses: &HashSet<House>,
-) -> Option<HashSet<House>> {
-    let observers = observers_mutex.lock().await;
+    old_snapshot: &Snapshot,
+    query: &SearchQuery,
+    operator_alerts: &OperatorAlerts,
+    settings_mutex: &SettingsMutex,
+) -> Option<Snapshot> {
+    // Work on a copy, so that commands are not blocked while the houses are fetched.
+    let observers = observers_mutex.lock().await.clone();
     if observers.is_empty() {
         log::info!("no observers, going to sleep until woken up");
         return None;
@@ -142,61 +891,180 @@ async fn get_houses_and_notify<Bot: Requester>(
         observers
             .iter()
             .fold(HashSet::new(), |mut acc, (_, cities)| {
-                acc.extend(cities);
+                acc.extend(cities.keys().cloned());
                 acc
             });
     log::trace!("Starting to query all houses");
-    let all_houses = api::query_houses_in_cities(all_cities.iter()).await;
+    let results = client
+        .query_houses_in_cities(all_cities.iter(), query)
+        .await;
     log::trace!("Done querying all houses");
-    match all_houses {
-        Ok(new_houses) => {
-            let new_houses: HashSet<House> = HashSet::from_iter(new_houses.into_iter());
-            let mut send_url = HashSet::<ChatId>::new();
-            for house in new_houses.difference(&old_houses) {
-                let observers = observers
-                    .iter()
-                    .filter(|(_, cities)| cities.contains(&house.city));
-                for (&chat_id, _) in observers {
-                    log::trace!(
-                        "Sending message that I found a new house to chat id {}",
-                        chat_id
-                    );
-                    bot.send_message(chat_id, format!("I found a new house! {}", house))
-                        .await
-                        .log_err();
-                    log::trace!(
-                        "Done sending message that I found a new house to chat id {}",
-                        chat_id
-                    );
-                    send_url.insert(chat_id);
+
+    let mut new_houses = Houses::new();
+    let mut new_stats = HashMap::new();
+    let mut failed_cities = HashSet::<City>::new();
+    let mut api_rejected_query = false;
+    for (city, result) in results {
+        match result {
+            Ok(listings) => {
+                for warning in &listings.warnings {
+                    log::warn!("{}", warning);
                 }
+                new_stats.insert(city.clone(), MarketStats::new(&city, &listings));
+                new_houses.extend(
+                    listings
+                        .houses
+                        .into_iter()
+                        .map(|house| (house.sku.clone(), house)),
+                );
             }
-            Some(new_houses)
-        }
-        Err(err) => {
-            for (&chat_id, _) in observers.iter() {
-                log::trace!(
-                    "Sending message that an error occurred while fetching houses from holland2stay {}",
-                    chat_id
+            Err(err) => {
+                log::error!(
+                    "An error occurred while fetching houses in {} from holland2stay: {}",
+                    city,
+                    err
                 );
-                bot.send_message(
+                operator_alerts.alert_on_error(bot, &err).await;
+                api_rejected_query |= matches!(err, Holland2StayError::GraphQl(_));
+                failed_cities.insert(city);
+            }
+        }
+    }
+    if !api_rejected_query {
+        operator_alerts.resolve().await;
+    }
+    // Keep the houses of the cities that failed, so they are not announced again once they recover.
+    new_houses.extend(
+        old_snapshot
+            .houses
+            .iter()
+            .filter(|(_, house)| failed_cities.contains(&house.city))
+            .map(|(sku, house)| (sku.clone(), house.clone())),
+    );
+    new_stats.extend(
+        old_snapshot
+            .stats
+            .iter()
+            .filter(|(city, _)| failed_cities.contains(city))
+            .map(|(city, stats)| (city.clone(), stats.clone())),
+    );
+
+    let events = events::diff_listings(&old_snapshot.houses, &new_houses);
+    let mut messages = Vec::<HouseMessage>::new();
+    {
+        let settings = settings_mutex.lock().await;
+        let default_settings = ChatSettings::default();
+        for event in events {
+            let recipients = observers
+                .iter()
+                .filter(|(_, cities)| {
+                    cities
+                        .get(&event.house().city)
+                        .is_some_and(|mode| mode.wants(&event))
+                })
+                .map(|(&chat_id, _)| (chat_id, settings.get(&chat_id).unwrap_or(&default_settings)))
+                .filter(|(_, settings)| settings.wants(&event));
+            for (chat_id, settings) in recipients {
+                let mut text = event.to_string();
+                if let Some(estimate) = settings.allowance_estimate(event.house()) {
+                    text.push_str(&format!("\nRent allowance: {}", estimate));
+                } else if let Some(analysis) = &event.house().price_analysis_text {
+                    // Without a profile, holland2stay's own note on the allowance is the best we have.
+                    text.push_str(&format!("\nRent allowance: {}", analysis));
+                }
+                messages.push(HouseMessage {
                     chat_id,
-                    "An error occurred while fetching houses from holland2stay.",
-                )
-                .await
-                .log_err();
-                log::trace!(
-                    "Done sending message that an error occurred while fetching houses from holland2stay {}",
-                    chat_id
-                );
+                    sku: event.house().sku.clone(),
+                    text,
+                    // Houses that are no longer listed have no product page to take the photo from.
+                    with_photo: event.kind() != EventKind::Disappeared,
+                });
             }
-            log::error!(
-                "An error occurred while fetching houses from holland2stay: {}",
-                err
-            );
-            None
         }
     }
+
+    let mut photos: HashMap<Sku, Option<Url>> = old_snapshot
+        .photos
+        .iter()
+        .filter(|(sku, _)| new_houses.contains_key(*sku))
+        .map(|(sku, photo)| (sku.clone(), photo.clone()))
+        .collect();
+    // The first poll announces every listed house, fetching all their photos would hold up the
+    // notifications for minutes.
+    let missing_photos: Vec<Sku> = if old_snapshot.houses.is_empty() {
+        Vec::new()
+    } else {
+        messages
+            .iter()
+            .filter(|message| message.with_photo && !photos.contains_key(&message.sku))
+            .map(|message| message.sku.clone())
+            .unique()
+            .take(MAX_PHOTO_FETCHES_PER_POLL)
+            .collect()
+    };
+    let fetched_photos: Vec<(Sku, Option<Url>)> = futures::stream::iter(missing_photos)
+        .map(|sku| {
+            let client = client.clone();
+            async move {
+                match fetch_main_photo(&client, &sku).await {
+                    Ok(photo) => Some((sku, photo)),
+                    Err(err) => {
+                        log::warn!("Could not fetch the details of house {}: {}", sku, err);
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_PHOTO_FETCHES)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+    photos.extend(fetched_photos);
+
+    for message in messages {
+        log::trace!(
+            "Sending event of house {} to chat id {}",
+            message.sku,
+            message.chat_id
+        );
+        let photo = photos
+            .get(&message.sku)
+            .filter(|_| message.with_photo)
+            .and_then(Option::as_ref);
+        send_house_message(bot, message.chat_id, message.text, photo).await;
+    }
+
+    for (&chat_id, cities) in observers.iter() {
+        let failed_cities: Vec<&City> = cities
+            .keys()
+            .filter(|city| failed_cities.contains(city))
+            .collect();
+        if failed_cities.is_empty() {
+            continue;
+        }
+        log::trace!(
+            "Sending message that an error occurred while fetching houses from holland2stay {}",
+            chat_id
+        );
+        bot.send_message(
+            chat_id,
+            format!(
+                "An error occurred while fetching houses in {} from holland2stay.",
+                itertools::join(failed_cities, ", ")
+            ),
+        )
+        .await
+        .log_err();
+        log::trace!(
+            "Done sending message that an error occurred while fetching houses from holland2stay {}",
+            chat_id
+        );
+    }
+    Some(Snapshot {
+        houses: new_houses,
+        photos,
+        stats: new_stats,
+    })
 }
 
 fn setup_periodic_check_timer(period: std::time::Duration) -> Receiver<()> {
@@ -213,6 +1081,62 @@ fn setup_periodic_check_timer(period: std::time::Duration) -> Receiver<()> {
     timer_rx
 }
 
+fn spawn_city_registry_refresh<B: Requester + Send + Sync + 'static>(
+    client: Holland2StayClient,
+    cities_mutex: CitiesMutex,
+    bot: B,
+    operator_alerts: OperatorAlerts,
+    period: std::time::Duration,
+) {
+    tokio::spawn(async move {
+        loop {
+            log::trace!("Starting to query all cities");
+            match client.query_cities().await {
+                Ok(cities) => {
+                    log::info!("Found {} cities on holland2stay", cities.iter().count());
+                    *cities_mutex.lock().await = cities;
+                }
+                Err(err) => {
+                    log::error!(
+                        "An error occurred while fetching cities from holland2stay: {}",
+                        err
+                    );
+                    operator_alerts.alert_on_error(&bot, &err).await;
+                }
+            }
+            tokio::time::sleep(period).await;
+        }
+    });
+}
+
+/// Continues the saved session of every linked account, or logs in again where holland2stay no
+/// longer accepts it, so that sessions that cannot be restored show up in the log at startup.
+fn spawn_linked_session_restore(client: Holland2StayClient, accounts: Arc<LinkedAccounts>) {
+    tokio::spawn(async move {
+        let user_ids = match accounts.user_ids() {
+            Ok(user_ids) => user_ids,
+            Err(err) => {
+                log::error!("Could not list the linked holland2stay accounts: {}", err);
+                return;
+            }
+        };
+        // One at a time, holland2stay rate limits logins.
+        for user_id in user_ids {
+            match accounts.client(&client, user_id).await {
+                Ok(Some(_)) => {
+                    log::info!("Restored the holland2stay session of {}", user_id)
+                }
+                Ok(None) => {}
+                Err(err) => log::error!(
+                    "Could not restore the holland2stay session of {}: {}",
+                    user_id,
+                    err
+                ),
+            }
+        }
+    });
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace"))
@@ -229,6 +1153,59 @@ async fn main() {
     dotenv::dotenv().ok();
 
     let bot = Bot::from_env();
+    let page_size = std::env::var("HOLLAND2STAY_PAGE_SIZE")
+        .ok()
+        .and_then(|page_size| page_size.parse().ok())
+        .filter(|&page_size| page_size > 0)
+        .unwrap_or(api::DEFAULT_PAGE_SIZE);
+    let query = SearchQuery::default()
+        .availability(Availability::ALL)
+        .page_size(page_size);
+    let mut client_builder = Holland2StayClient::builder();
+    if let Ok(api_url) = std::env::var("HOLLAND2STAY_API_URL") {
+        client_builder = client_builder.api_url(
+            api_url
+                .parse()
+                .expect("Could not parse HOLLAND2STAY_API_URL"),
+        );
+    }
+    let client = client_builder
+        .build()
+        .expect("Could not build holland2stay client");
+
+    // The listings are public, the only holland2stay logins are those of linked accounts.
+    let accounts = CredentialsKey::from_env().map(|key| {
+        let key = key.expect("Could not parse HOLLAND2STAY_CREDENTIALS_KEY");
+        let dir = std::env::var("HOLLAND2STAY_ACCOUNTS_DIR").unwrap_or_else(|_| "accounts".into());
+        Arc::new(LinkedAccounts::new(dir, &key))
+    });
+    match &accounts {
+        Some(accounts) => spawn_linked_session_restore(client.clone(), accounts.clone()),
+        None => {
+            log::warn!("HOLLAND2STAY_CREDENTIALS_KEY is not set, users cannot link their accounts")
+        }
+    }
+
+    if std::env::var(ALLOWANCE_RULES_VAR).is_err() {
+        log::warn!(
+            "{} is not set, rent allowance is estimated with the {} limits",
+            ALLOWANCE_RULES_VAR,
+            AllowanceRules::DEFAULT_YEAR
+        );
+    }
+    LazyLock::force(&ALLOWANCE_RULES);
+
+    let operator_alerts = OperatorAlerts::new(
+        std::env::var("HOLLAND2STAY_OPERATOR_CHAT_ID")
+            .ok()
+            .map(|chat_id| {
+                ChatId(
+                    chat_id
+                        .parse()
+                        .expect("Could not parse HOLLAND2STAY_OPERATOR_CHAT_ID"),
+                )
+            }),
+    );
 
     let addr = ([127, 0, 0, 1], 8000).into();
     let url = ngrok::fetch_ngrok_url()
@@ -241,25 +1218,46 @@ async fn main() {
 
     let mut on_check_houses = setup_periodic_check_timer(std::time::Duration::from_secs(15));
 
-    let observers: ObserverMutex = Arc::new(Mutex::new(HashMap::new()));
-    let houses_mutex: HousesMutex = Arc::new(Mutex::new(HashSet::new()));
+    let state = BotState {
+        observers: Arc::new(Mutex::new(HashMap::new())),
+        snapshot: Arc::new(Mutex::new(Arc::default())),
+        cities: Arc::new(Mutex::new(CityRegistry::default())),
+        settings: Arc::new(Mutex::new(HashMap::new())),
+        client: client.clone(),
+        accounts,
+    };
+
+    spawn_city_registry_refresh(
+        client.clone(),
+        state.cities.clone(),
+        bot.clone(),
+        operator_alerts.clone(),
+        std::time::Duration::from_secs(60 * 60),
+    );
 
-    let observers_clone = observers.clone();
-    let houses_clone = houses_mutex.clone();
+    let observers_clone = state.observers.clone();
+    let snapshot_clone = state.snapshot.clone();
+    let settings_clone = state.settings.clone();
     let mut bot_clone = bot.clone();
     tokio::spawn(async move {
         loop {
+            let old_snapshot = snapshot_clone.lock().await.clone();
+            if let Some(new_snapshot) = get_houses_and_notify(
+                &client,
+                &observers_clone,
+                &mut bot_clone,
+                &old_snapshot,
+                &query,
+                &operator_alerts,
+                &settings_clone,
+            )
+            .await
             {
-                let mut houses = houses_clone.lock().await;
-                if let Some(new_houses) =
-                    get_houses_and_notify(&observers_clone, &mut bot_clone, &houses).await
-                {
-                    *houses = new_houses;
-                }
+                *snapshot_clone.lock().await = Arc::new(new_snapshot);
             }
 
             let now = std::time::Instant::now();
-            while let None = on_check_houses.recv().await {}
+            while on_check_houses.recv().await.is_none() {}
             let slept_for = std::time::Instant::now().duration_since(now);
             log::info!("Awake! slept for {:.2}s", slept_for.as_secs_f64());
         }
@@ -268,9 +1266,7 @@ async fn main() {
     tokio::spawn(async move {
         Command::repl_with_listener(
             bot,
-            move |bot: Bot, msg: Message, cmd: Command| {
-                answer(bot, msg, cmd, observers.clone(), houses_mutex.clone())
-            },
+            move |bot: Bot, msg: Message, cmd: Command| answer(bot, msg, cmd, state.clone()),
             listener,
         )
         .await;
@@ -281,3 +1277,107 @@ async fn main() {
         .expect("failed to listen for shutdown signal");
     log::info!("shutdown signal received, exiting");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holland2stay_rs::api::CostBreakdown;
+
+    fn house(availability: Option<Availability>) -> House {
+        House {
+            name: "Kruisplein 12A".to_string(),
+            sku: Sku::from("RTD-KRP-12A".to_string()),
+            url: None,
+            city: City {
+                id: "25".parse().unwrap(),
+                name: "Rotterdam".to_string(),
+            },
+            building_name: None,
+            living_area: None,
+            rooms: None,
+            finishing: None,
+            resident_type: None,
+            maximum_number_of_persons: None,
+            energy_label: None,
+            floor: None,
+            minimum_stay_months: None,
+            price: None,
+            costs: CostBreakdown::default(),
+            allowance_price: None,
+            price_analysis_text: None,
+            availability,
+            available_from: None,
+            start_date: None,
+            current_lottery_subscribers: None,
+            contract_type: None,
+            offer_text: None,
+            offer_text_two: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_watch_args() {
+        assert_eq!(
+            parse_watch_args("Den Haag lottery"),
+            ("Den Haag", WatchMode::Lottery)
+        );
+        assert_eq!(
+            parse_watch_args("Den Haag"),
+            ("Den Haag", WatchMode::Direct)
+        );
+        assert_eq!(
+            parse_watch_args("  Den Haag   both  "),
+            ("Den Haag", WatchMode::Both)
+        );
+        assert_eq!(
+            parse_watch_args("Rotterdam DiReCt"),
+            ("Rotterdam", WatchMode::Direct)
+        );
+        assert_eq!(
+            parse_watch_args("Rotterdam"),
+            ("Rotterdam", WatchMode::Direct)
+        );
+    }
+
+    #[test]
+    fn test_watch_mode_from_name() {
+        assert_eq!(WatchMode::from_name(" LOTTERY "), Some(WatchMode::Lottery));
+        assert_eq!(WatchMode::from_name("Both"), Some(WatchMode::Both));
+        assert_eq!(WatchMode::from_name("haag"), None);
+    }
+
+    #[test]
+    fn test_watch_mode_includes() {
+        let direct = Some(Availability::DirectBooking);
+        let lottery = Some(Availability::Lottery);
+        assert!(WatchMode::Direct.includes(direct));
+        assert!(!WatchMode::Direct.includes(lottery));
+        assert!(WatchMode::Lottery.includes(lottery));
+        assert!(!WatchMode::Lottery.includes(direct));
+        assert!(WatchMode::Both.includes(direct) && WatchMode::Both.includes(lottery));
+        // Unknown availabilities are never missed.
+        assert!(WatchMode::Direct.includes(None));
+        assert!(WatchMode::Lottery.includes(None));
+    }
+
+    #[test]
+    fn test_watch_mode_wants() {
+        let lottery_house = house(Some(Availability::Lottery));
+        assert!(!WatchMode::Direct.wants(&ListingEvent::Appeared(lottery_house.clone())));
+        assert!(WatchMode::Lottery.wants(&ListingEvent::Appeared(lottery_house.clone())));
+        // A house moving out of direct booking is still reported to direct watchers.
+        let moved_to_lottery = ListingEvent::AvailabilityChanged {
+            house: lottery_house,
+            old: Some(Availability::DirectBooking),
+        };
+        assert!(WatchMode::Direct.wants(&moved_to_lottery));
+        assert!(WatchMode::Lottery.wants(&moved_to_lottery));
+        let direct_house = house(Some(Availability::DirectBooking));
+        assert!(!WatchMode::Lottery.wants(&ListingEvent::Appeared(direct_house.clone())));
+        let moved_to_direct = ListingEvent::AvailabilityChanged {
+            house: direct_house,
+            old: Some(Availability::Lottery),
+        };
+        assert!(WatchMode::Lottery.wants(&moved_to_direct));
+    }
+}
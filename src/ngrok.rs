@@ -1,4 +1,3 @@
-use reqwest;
 use serde::Deserialize;
 
 #[derive(Debug, thiserror::Error)]
@@ -27,10 +26,9 @@ pub async fn fetch_ngrok_url() -> Result<String, NgrokError> {
         .json::<ApiResponse>()
         .await?;
 
-    Ok(resp
-        .tunnels
+    resp.tunnels
         .into_iter()
         .find(|t| t.name == "holland2stay-bot")
         .map(|t| t.public_url)
-        .ok_or_else(|| NgrokError::NgrokTunelNotFound)?)
+        .ok_or(NgrokError::NgrokTunelNotFound)
 }
@@ -0,0 +1,275 @@
+//! A local stand-in for holland2stay.com and its graphql api, serving the recorded responses in
+//! `fixtures/`.
+
+use std::time::Duration;
+
+use reqwest::Url;
+use wiremock::{
+    Mock, MockServer, Request, ResponseTemplate,
+    matchers::{body_string_contains, header_regex, method, path},
+};
+
+use crate::api::{City, Holland2StayClient, Holland2StayClientBuilder, RetryPolicy};
+use crate::auth::Auth;
+
+const SESSION_COOKIE: &str = "next-auth.session-token";
+
+pub fn fixture(name: &str) -> serde_json::Value {
+    let path = format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let contents = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Could not read fixture {}: {}", path, e));
+    serde_json::from_str(&contents).unwrap_or_else(|e| panic!("Invalid fixture {}: {}", path, e))
+}
+
+/// The city of the recorded listings.
+pub fn rotterdam() -> City {
+    City {
+        id: "25".parse().unwrap(),
+        name: "Rotterdam".to_string(),
+    }
+}
+
+/// A city without recorded listings.
+pub fn delft() -> City {
+    City {
+        id: "26".parse().unwrap(),
+        name: "Delft".to_string(),
+    }
+}
+
+/// The credentials the fake website accepts.
+pub fn auth() -> Auth {
+    Auth::new("jane@example.com".to_string(), "hunter2".to_string())
+}
+
+/// The values of an `eq` or `in` filter of a products query.
+fn filter_values(filters: &serde_json::Value, attribute_code: &str) -> Option<Vec<String>> {
+    let filter = filters.get(attribute_code)?;
+    let values = match (filter.get("eq"), filter.get("in")) {
+        (Some(value), _) => vec![value.clone()],
+        (None, Some(serde_json::Value::Array(values))) => values.clone(),
+        _ => return None,
+    };
+    Some(
+        values
+            .iter()
+            .map(|value| match value {
+                serde_json::Value::String(s) => s.clone(),
+                value => value.to_string(),
+            })
+            .collect(),
+    )
+}
+
+/// Answers product details queries with the recorded details of the requested sku, if any.
+fn respond_to_product_details_query(variables: &serde_json::Value) -> ResponseTemplate {
+    let mut response = fixture("product_details.json");
+    let products = &mut response["data"]["products"];
+    let items: Vec<serde_json::Value> = products["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|item| item["sku"] == variables["sku"])
+        .cloned()
+        .collect();
+    products["total_count"] = items.len().into();
+    products["items"] = items.into();
+    ResponseTemplate::new(200).set_body_json(response)
+}
+
+/// Answers the account queries with the recorded data of the logged in customer, as long as the
+/// recorded bearer token is sent along.
+fn respond_to_account_query(request: &Request, fixture_name: &str) -> ResponseTemplate {
+    let bearer_token = fixture("session.json")["accessToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let authorization = request
+        .headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+    if authorization != Some(format!("Bearer {}", bearer_token).as_str()) {
+        return ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "errors": [{
+                "message": "The current customer isn't authorized.",
+                "extensions": { "category": "graphql-authorization" },
+            }],
+            "data": { "customer": null },
+        }));
+    }
+    ResponseTemplate::new(200).set_body_json(fixture(fixture_name))
+}
+
+/// Answers graphql queries the way the holland2stay api does. Products queries without a city
+/// filter get the city aggregation, others get the matching page of recorded listings.
+fn respond_to_graphql_query(request: &Request) -> ResponseTemplate {
+    let body: serde_json::Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(_) => return ResponseTemplate::new(400),
+    };
+    let variables = &body["variables"];
+    match body["operationName"].as_str() {
+        Some("GetProductDetails") => return respond_to_product_details_query(variables),
+        Some("GetCustomer") => return respond_to_account_query(request, "customer.json"),
+        Some("GetReservations") => return respond_to_account_query(request, "reservations.json"),
+        Some("GetLotteryRegistrations") => {
+            return respond_to_account_query(request, "lottery_registrations.json");
+        }
+        _ => {}
+    }
+    let Some(cities) = filter_values(&variables["filters"], "city") else {
+        return ResponseTemplate::new(200).set_body_json(fixture("cities.json"));
+    };
+    let page_size = variables["pageSize"].as_u64().unwrap_or(20) as usize;
+    let current_page = variables["currentPage"].as_u64().unwrap_or(1) as usize;
+
+    let mut response = fixture("products_rotterdam.json");
+    let products = &mut response["data"]["products"];
+    let items: Vec<serde_json::Value> = products["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|item| cities.contains(&item["city"].to_string()))
+        .cloned()
+        .collect();
+    products["total_count"] = items.len().into();
+    products["items"] = items
+        .into_iter()
+        .skip((current_page - 1) * page_size)
+        .take(page_size)
+        .collect();
+    ResponseTemplate::new(200).set_body_json(response)
+}
+
+pub struct FakeHolland2Stay {
+    server: MockServer,
+}
+
+impl FakeHolland2Stay {
+    /// A server that answers every request with a 404, for tests that mount their own mocks.
+    pub async fn empty() -> Self {
+        FakeHolland2Stay {
+            server: MockServer::start().await,
+        }
+    }
+
+    /// A server serving both the graphql api and the login flow.
+    pub async fn start() -> Self {
+        let server = Self::empty().await;
+        server.mount_products().await;
+        server.mount_csrf().await;
+        server.mount_session().await;
+        server.mount_credentials_callback().await;
+        server
+    }
+
+    pub fn mock_server(&self) -> &MockServer {
+        &self.server
+    }
+
+    pub fn base_url(&self) -> Url {
+        Url::parse(&format!("{}/", self.server.uri())).unwrap()
+    }
+
+    pub fn api_url(&self) -> Url {
+        self.base_url().join("graphql/").unwrap()
+    }
+
+    /// A builder for clients of this server that retry without waiting and are not rate limited.
+    pub fn client_builder(&self) -> Holland2StayClientBuilder {
+        Holland2StayClient::builder()
+            .api_url(self.api_url())
+            .retry_policy(RetryPolicy {
+                initial_backoff: Duration::ZERO,
+                ..RetryPolicy::default()
+            })
+            .rate_limit(Duration::ZERO)
+    }
+
+    pub fn client(&self) -> Holland2StayClient {
+        self.client_builder().build().unwrap()
+    }
+
+    pub async fn mount_products(&self) {
+        Mock::given(method("POST"))
+            .and(path("/graphql/"))
+            .respond_with(respond_to_graphql_query)
+            .mount(&self.server)
+            .await;
+    }
+
+    pub async fn mount_csrf(&self) {
+        Mock::given(method("GET"))
+            .and(path("/api/auth/csrf"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture("csrf.json")))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// The session is anonymous until the session cookie set by a successful login is sent along.
+    /// Like on the website, sessions expire 30 days after they are requested.
+    pub async fn mount_session(&self) {
+        Mock::given(method("GET"))
+            .and(path("/api/auth/session"))
+            .and(header_regex("cookie", SESSION_COOKIE))
+            .respond_with(|_: &Request| {
+                let mut session = fixture("session.json");
+                session["expires"] = (chrono::Utc::now() + chrono::TimeDelta::days(30))
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                    .into();
+                ResponseTemplate::new(200).set_body_json(session)
+            })
+            .with_priority(2)
+            .mount(&self.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/auth/session"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(fixture("session_anonymous.json")),
+            )
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Accepts the password "hunter2" together with the recorded csrf token, and redirects to
+    /// where the website says what went wrong otherwise.
+    pub async fn mount_credentials_callback(&self) {
+        let csrf_token = fixture("csrf.json")["csrfToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let csrf_token_field = format!("csrfToken={}", csrf_token);
+        Mock::given(method("POST"))
+            .and(path("/api/auth/callback/credentials"))
+            .and(body_string_contains("password=hunter2"))
+            .and(body_string_contains(csrf_token_field.clone()))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header(
+                        "set-cookie",
+                        format!("{}=fake-session; Path=/; HttpOnly", SESSION_COOKIE),
+                    )
+                    .insert_header("location", "/"),
+            )
+            .with_priority(2)
+            .mount(&self.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/callback/credentials"))
+            .and(body_string_contains(csrf_token_field))
+            .respond_with(ResponseTemplate::new(302).insert_header(
+                "location",
+                "/api/auth/error?error=CredentialsSignin&provider=credentials",
+            ))
+            .with_priority(3)
+            .mount(&self.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/callback/credentials"))
+            .respond_with(
+                ResponseTemplate::new(302).insert_header("location", "/api/auth/signin?csrf=true"),
+            )
+            .mount(&self.server)
+            .await;
+    }
+}